@@ -1,10 +1,9 @@
-use std::io::Read;
-use anyhow::Error;
-use isahc::ReadResponseExt;
 use serde::{Deserialize, Serialize};
 
-use crate::{http, MAIL_API_URL};
-use crate::http::Client;
+use crate::client::MailTmClient;
+use crate::error::MailTmError;
+use crate::http::{Method, Request};
+use crate::timestamp::Timestamp;
 use crate::user::User;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -16,11 +15,10 @@ pub struct Account {
     pub used: i64,
     #[serde(rename = "isDisabled")]
     pub is_disabled: bool,
-    #[serde(rename = "createdAt")]
-    pub created_at: serde_json::Value,
-    #[serde(rename = "updatedAt")]
-    pub updated_at: ::serde_json::Value,
-    //TODO these are not values, they're utc date
+    #[serde(rename = "createdAt", default)]
+    pub created_at: Option<Timestamp>,
+    #[serde(rename = "updatedAt", default)]
+    pub updated_at: Option<Timestamp>,
     #[serde(rename = "@context")]
     pub context: Option<String>,
     #[serde(rename = "@id")]
@@ -49,137 +47,118 @@ impl Account {
     }
 }
 
-pub(crate) fn create(user: &User) -> Result<Account, Error> {
-    let client = Client::new()?.build()?;
-
-    log::debug!("Creating account for user {:?}", user);
-
+fn create_request(client: &MailTmClient, user: &User) -> Result<Request<String>, MailTmError> {
     let json = serde_json::json!(Account::from_user(user));
-    let json_str = json.to_string();
-    let mut response = client
-        .post(format!("{}/accounts", MAIL_API_URL.to_owned()).as_str(), json_str)?;
-
-    let code = response.status();
+    Ok(client
+        .request(Method::POST, "/accounts", None)
+        .body(json.to_string())?)
+}
 
-    let response_str = {
-        let mut buffer = String::new();
-        response.body_mut().read_to_string(&mut buffer)?;
-        buffer
-    };
+fn get_request(client: &MailTmClient, token: &str, id: &str) -> Result<Request<String>, MailTmError> {
+    Ok(client
+        .request(Method::GET, &format!("/accounts/{}", id), Some(token))
+        .body(String::new())?)
+}
 
-    http::check_response_status(&code, &response_str)?;
+fn delete_request(client: &MailTmClient, token: &str, id: &str) -> Result<Request<String>, MailTmError> {
+    Ok(client
+        .request(Method::DELETE, &format!("/accounts/{}", id), Some(token))
+        .body(String::new())?)
+}
 
-    log::trace!("Created account: {}", response_str);
-    Ok(serde_json::from_str(&response_str)?)
+fn me_request(client: &MailTmClient, token: &str) -> Result<Request<String>, MailTmError> {
+    Ok(client
+        .request(Method::GET, "/me", Some(token))
+        .body(String::new())?)
 }
 
-pub(crate) fn get(token: &str, id: &str) -> Result<Account, Error> {
-    let client = Client::new()?.with_auth(&token)?.build()?;
+pub(crate) fn create(client: &MailTmClient, user: &User) -> Result<Account, MailTmError> {
+    log::debug!("Creating account for user {:?}", user);
+    client.call(create_request(client, user)?)
+}
 
+pub(crate) fn get(client: &MailTmClient, token: &str, id: &str) -> Result<Account, MailTmError> {
     log::debug!("Searching for account with id {}", id);
+    client.call(get_request(client, token, id)?)
+}
 
-    let mut response = client
-        .get(&format!("{}/accounts/{}", MAIL_API_URL.to_owned(), id))?;
-
-    let code = response.status();
-
-    let response_str = {
-        let mut buffer = String::new();
-        response.body_mut().read_to_string(&mut buffer)?;
-        buffer
-    };
-
-    http::check_response_status(&code, &response_str)?;
+pub(crate) fn delete(client: &MailTmClient, token: &str, id: &str) -> Result<(), MailTmError> {
+    log::debug!("Deleting account with id {}", id);
+    client.execute(delete_request(client, token, id)?)?;
+    Ok(())
+}
 
-    log::trace!("Retrieved a user: {}", response_str);
-    Ok(serde_json::from_str(&response_str)?)
+pub(crate) fn me(client: &MailTmClient, token: &str) -> Result<Account, MailTmError> {
+    log::debug!("Getting me");
+    client.call(me_request(client, token)?)
 }
 
-pub(crate) fn delete(token: &str, id: &str) -> Result<(), Error> {
-    let client = Client::new()?.with_auth(&token)?.build()?;
+#[cfg(feature = "async")]
+pub(crate) async fn create_async(client: &MailTmClient, user: &User) -> Result<Account, MailTmError> {
+    log::debug!("Creating account for user {:?}", user);
+    client.call_async(create_request(client, user)?).await
+}
 
+#[cfg(feature = "async")]
+pub(crate) async fn get_async(client: &MailTmClient, token: &str, id: &str) -> Result<Account, MailTmError> {
     log::debug!("Searching for account with id {}", id);
+    client.call_async(get_request(client, token, id)?).await
+}
 
-
-    let response = client
-        .delete(&format!("{}/accounts/{}", MAIL_API_URL.to_owned(), id))?;
-
-    let code = response.status();
-
-    http::check_response_status(&code, "")?;
-
-    log::trace!("Deleted user with id {}", id);
+#[cfg(feature = "async")]
+pub(crate) async fn delete_async(client: &MailTmClient, token: &str, id: &str) -> Result<(), MailTmError> {
+    log::debug!("Deleting account with id {}", id);
+    client.execute_async(delete_request(client, token, id)?).await?;
     Ok(())
 }
 
-pub(crate) fn me(token: &str) -> Result<Account, Error> {
-    let client = Client::new()?.with_auth(&token)?.build()?;
-
+#[cfg(feature = "async")]
+pub(crate) async fn me_async(client: &MailTmClient, token: &str) -> Result<Account, MailTmError> {
     log::debug!("Getting me");
-
-    let builder = client
-        .get(&format!("{}/me", MAIL_API_URL.to_owned()));
-
-    let mut response = builder?;
-
-    let code = response.status();
-
-    let mut response_str = String::new();
-    let n = response.body_mut().read_to_string(&mut response_str)?;
-
-    http::check_response_status(&code, &response_str)?;
-
-    log::trace!("Retrieved me: {}", response_str);
-    Ok(serde_json::from_str(&response_str)?)
+    client.call_async(me_request(client, token)?).await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::inbox::TempInbox;
+    use crate::mock::MockServer;
     use crate::token;
 
-    fn test_accounts_create() -> Result<(), Error> {
+    #[test]
+    fn test_accounts_create() -> Result<(), MailTmError> {
         pretty_env_logger::try_init().ok();
-
-        let user = User::default().with_domain(&crate::domains::domains()?.any().domain);
-        assert_eq!(
-            create(&user)?
-                .address
-                .as_str()
-                .is_empty(),
-            false
-        );
+        let server = MockServer::start()?;
+        let inbox = TempInbox::with_client(&server.client()?)?;
+        assert!(!inbox.address().is_empty());
+        assert!(inbox.account().id.is_some());
         Ok(())
     }
 
-    async fn test_accounts() -> Result<(), Error> {
+    #[test]
+    fn test_accounts() -> Result<(), MailTmError> {
         pretty_env_logger::try_init().ok();
-        let user = User::default().with_domain(&crate::domains::domains()?.any().domain);
-
-        let create = create(&user).unwrap();
+        let server = MockServer::start()?;
+        let client = server.client()?;
+        let user = User::default().with_domain(&crate::domains::domains(&client)?.any_active().expect("no active domains").domain);
 
-        let token = token(&user).unwrap();
+        let create = create(&client, &user).unwrap();
 
+        let token = token::token(&client, &user).unwrap();
 
-        assert_eq!(
-            create
-                .address
-                .as_str()
-                .is_empty(),
-            false
-        );
+        assert!(!create.address.is_empty());
 
         let id = create.id.unwrap();
 
-        let get = get(&token.token, &id)?;
+        let get = get(&client, &token.token, &id)?;
 
-        assert_eq!(get.id.unwrap(), id.clone());
+        assert_eq!(get.id.unwrap(), id);
 
-        let me = me(&token.token)?;
+        let me = me(&client, &token.token)?;
 
-        assert_eq!(me.id.unwrap(), id.clone());
+        assert_eq!(me.id.unwrap(), id);
 
-        delete(&token.token, &id).unwrap();
+        delete(&client, &token.token, &id).unwrap();
 
         Ok(())
     }
@@ -0,0 +1,157 @@
+//! Async equivalents of the free functions in the crate root
+//!
+//! Enabled with the `async` feature. These share the model types with the blocking api and run on
+//! the same [`default_client`], so blocking and async calls share one connection pool. They are
+//! runtime agnostic and can be awaited from tokio, async-std or a plain executor.
+//!
+//! # Example
+//! ```no_run
+//! use mail_tm_rs::asynchronous::{create_account, domains, token};
+//! use mail_tm_rs::update_token;
+//! use mail_tm_rs::user::User;
+//!
+//! async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//!     let user = User::default().with_domain(&domains().await?.any_active().ok_or("no active domains")?.domain);
+//!     let account = create_account(&user).await?;
+//!     let user = update_token(&user, &token(&user).await?.token);
+//!     Ok(())
+//! }
+//! ```
+
+use std::time::Duration;
+
+use futures_lite::{AsyncWrite, Stream};
+
+use crate::accounts::Account;
+use crate::default_client;
+use crate::domains::Domain;
+use crate::error::MailTmError;
+use crate::events::Event;
+use crate::hydra::HydraCollection;
+use crate::messages::{Attachment, Message};
+use crate::token::Token;
+use crate::user::User;
+
+/// Async version of [`crate::create_account`]
+pub async fn create_account(user: &User) -> Result<Account, MailTmError> {
+    default_client()?.create_account_async(user).await
+}
+
+/// Async version of [`crate::get_account`]
+pub async fn get_account(user: &User, id: &str) -> Result<Account, MailTmError> {
+    default_client()?.get_account_async(user, id).await
+}
+
+/// Async version of [`crate::delete_account`]
+pub async fn delete_account(user: &User, id: &str) -> Result<(), MailTmError> {
+    default_client()?.delete_account_async(user, id).await
+}
+
+/// Async version of [`crate::me`]
+pub async fn me(user: &User) -> Result<Account, MailTmError> {
+    default_client()?.me_async(user).await
+}
+
+/// Async version of [`crate::domains()`]
+pub async fn domains() -> Result<HydraCollection<Domain>, MailTmError> {
+    default_client()?.domains_async().await
+}
+
+/// Async version of [`crate::list_domains`]
+pub async fn list_domains(page: usize) -> Result<HydraCollection<Domain>, MailTmError> {
+    default_client()?.list_domains_async(page).await
+}
+
+/// Async version of [`crate::get_domain`]
+pub async fn get_domain(id: &str) -> Result<Domain, MailTmError> {
+    default_client()?.get_domain_async(id).await
+}
+
+/// Async version of [`crate::find_domain`]
+pub async fn find_domain(name: &str) -> Result<Option<Domain>, MailTmError> {
+    default_client()?.find_domain_async(name).await
+}
+
+/// Async version of [`crate::list_messages`]
+pub async fn list_messages(user: &User, page: Option<usize>) -> Result<HydraCollection<Message>, MailTmError> {
+    default_client()?.list_messages_async(user, page).await
+}
+
+/// Async version of [`crate::get_message`]
+pub async fn get_message(user: &User, id: &str) -> Result<Message, MailTmError> {
+    default_client()?.get_message_async(user, id).await
+}
+
+/// Async version of [`crate::delete_message`]
+pub async fn delete_message(user: &User, id: &str) -> Result<(), MailTmError> {
+    default_client()?.delete_message_async(user, id).await
+}
+
+/// Async version of [`crate::mark_seen`]
+pub async fn mark_seen(user: &User, id: &str, seen: bool) -> Result<Message, MailTmError> {
+    default_client()?.mark_seen_async(user, id, seen).await
+}
+
+/// Async version of [`crate::get_message_source`]
+pub async fn get_message_source(user: &User, id: &str) -> Result<String, MailTmError> {
+    default_client()?.get_message_source_async(user, id).await
+}
+
+/// Async version of [`crate::download_message_source`]
+pub async fn download_message_source<W: AsyncWrite + Unpin>(user: &User, message: &Message, writer: W) -> Result<u64, MailTmError> {
+    default_client()?.download_message_source_async(user, message, writer).await
+}
+
+/// Async version of [`crate::download_attachment`]
+pub async fn download_attachment<W: AsyncWrite + Unpin>(user: &User, attachment: &Attachment, writer: W) -> Result<u64, MailTmError> {
+    default_client()?.download_attachment_async(user, attachment, writer).await
+}
+
+/// Async version of [`crate::get_attachment`]
+pub async fn get_attachment(user: &User, attachment: &Attachment) -> Result<Vec<u8>, MailTmError> {
+    default_client()?.get_attachment_async(user, attachment).await
+}
+
+/// Async version of [`crate::wait_for_message`]
+pub async fn wait_for_message<P: FnMut(&Message) -> bool>(user: &User, predicate: P, timeout: Duration) -> Result<Message, MailTmError> {
+    default_client()?.wait_for_message_async(user, predicate, timeout).await
+}
+
+/// Async version of [`crate::subscribe`], yielding events through a [`futures_lite::Stream`]
+pub fn subscribe(user: &User, account_id: &str) -> Result<impl Stream<Item = Result<Event, MailTmError>> + Send + 'static, MailTmError> {
+    Ok(default_client()?.subscribe_async(user, account_id))
+}
+
+/// Async version of [`crate::login`]
+pub async fn login(address: &str, password: &str) -> Result<User, MailTmError> {
+    default_client()?.login_async(address, password).await
+}
+
+/// Async version of [`crate::token()`]
+pub async fn token(user: &User) -> Result<Token, MailTmError> {
+    default_client()?.token_async(user).await
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::future::block_on;
+
+    use super::*;
+    use crate::mock::MockServer;
+
+    #[test]
+    fn test_async_account_lifecycle() -> Result<(), MailTmError> {
+        let server = MockServer::start()?;
+        let client = server.client()?;
+        block_on(async {
+            let user = User::default().with_domain(&client.domains_async().await?.any_active().expect("no active domains").domain);
+            let account = client.create_account_async(&user).await?;
+            let user = crate::update_token(&user, &client.token_async(&user).await?.token);
+
+            assert_eq!(client.me_async(&user).await?.id, account.id);
+            assert_eq!(client.list_messages_async(&user, None).await?.total_items, 0);
+
+            client.delete_account_async(&user, &account.id.unwrap()).await
+        })
+    }
+}
@@ -0,0 +1,204 @@
+//! Token bookkeeping for [`MailTmClient`]
+//!
+//! Mail-TM tokens are short lived JWTs. A client remembers the freshest token it has seen for each
+//! address, refreshes it through `/token` using the stored password shortly before it expires, and
+//! retries a request once if the api rejects the token anyway.
+//!
+//! [`MailTmClient`]: crate::MailTmClient
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::token;
+use crate::user::User;
+
+/// Why a token was refreshed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefreshReason {
+    /// The token was about to expire
+    Expiring,
+    /// The api rejected the token with a 401
+    Rejected,
+}
+
+/// Handed to the refresh hook every time a token is replaced
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefreshEvent {
+    pub address: String,
+    pub reason: RefreshReason,
+    pub token: String,
+    pub expires_at: Option<SystemTime>,
+}
+
+pub(crate) type RefreshHook = Arc<dyn Fn(&RefreshEvent) + Send + Sync>;
+
+pub(crate) struct Auth {
+    tokens: RwLock<HashMap<String, String>>,
+    enabled: bool,
+    margin: Duration,
+    hook: Option<RefreshHook>,
+}
+
+impl Auth {
+    pub fn new(enabled: bool, margin: Duration, hook: Option<RefreshHook>) -> Auth {
+        Auth {
+            tokens: RwLock::new(HashMap::new()),
+            enabled,
+            margin,
+            hook,
+        }
+    }
+
+    /// The token expiring last out of the one the user carries and the last one refreshed for it
+    pub fn current(&self, user: &User) -> String {
+        let tokens = self.tokens.read().unwrap();
+        match tokens.get(&key(user)) {
+            Some(cached) if token::expiry(cached) > token::expiry(&user.email_token) => cached.clone(),
+            _ => user.email_token.clone(),
+        }
+    }
+
+    /// Whether the user has a password to refresh with and refreshing is turned on
+    pub fn can_refresh(&self, user: &User) -> bool {
+        self.enabled && !user.password.is_empty()
+    }
+
+    /// Whether the token expires within the refresh margin
+    pub fn needs_refresh(&self, user: &User, token: &str) -> bool {
+        self.can_refresh(user)
+            && token::expiry(token)
+                .map(|expiry| expiry <= SystemTime::now() + self.margin)
+                .unwrap_or(false)
+    }
+
+    pub fn store(&self, user: &User, token: &str, reason: RefreshReason) {
+        self.tokens
+            .write()
+            .unwrap()
+            .insert(key(user), token.to_string());
+
+        if let Some(hook) = &self.hook {
+            hook(&RefreshEvent {
+                address: user.address(),
+                reason,
+                token: token.to_string(),
+                expires_at: token::expiry(token),
+            });
+        }
+    }
+
+    /// Drops the token refreshed for the user, if any
+    pub fn forget(&self, user: &User) {
+        self.tokens.write().unwrap().remove(&key(user));
+    }
+}
+
+fn key(user: &User) -> String {
+    user.address().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::UNIX_EPOCH;
+
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+
+    use super::*;
+
+    fn jwt(exp: SystemTime) -> String {
+        let exp = exp.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let payload = URL_SAFE_NO_PAD.encode(format!(r#"{{"exp":{}}}"#, exp));
+        format!("eyJhbGciOiJIUzI1NiJ9.{}.signature", payload)
+    }
+
+    #[test]
+    fn test_refresh_bookkeeping() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let hook_events = events.clone();
+        let auth = Auth::new(
+            true,
+            Duration::from_secs(30),
+            Some(Arc::new(move |event: &RefreshEvent| hook_events.lock().unwrap().push(event.clone()))),
+        );
+
+        let stale = jwt(SystemTime::now() + Duration::from_secs(10));
+        let fresh = jwt(SystemTime::now() + Duration::from_secs(3600));
+        let user = crate::update_token(&User::new("Someone", "hunter2", "example.com"), &stale);
+
+        assert!(auth.needs_refresh(&user, &stale));
+        assert!(!auth.needs_refresh(&user, &fresh));
+        assert!(!auth.needs_refresh(&user, "not a jwt"));
+        assert_eq!(auth.current(&user), stale);
+
+        auth.store(&user, &fresh, RefreshReason::Expiring);
+        assert_eq!(auth.current(&user), fresh);
+        let relogged = jwt(SystemTime::now() + Duration::from_secs(7200));
+        assert_eq!(auth.current(&crate::update_token(&user, &relogged)), relogged);
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].address, "Someone@example.com");
+        assert_eq!(events[0].reason, RefreshReason::Expiring);
+        assert!(events[0].expires_at.is_some());
+
+        auth.forget(&user);
+        assert_eq!(auth.current(&user), stale);
+
+        let no_password = User { password: String::new(), ..user };
+        assert!(!auth.needs_refresh(&no_password, &stale));
+    }
+
+    #[test]
+    fn test_refresh_against_mock() -> Result<(), crate::error::MailTmError> {
+        use crate::client::MailTmClient;
+        use crate::mock::MockServer;
+        use crate::transport::{default_transport, CassetteTransport};
+
+        let server = MockServer::start()?;
+        let recorder = CassetteTransport::record_in_memory(default_transport()?);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let hook_events = events.clone();
+        let client = MailTmClient::builder()?
+            .with_base_url(server.url())
+            .with_transport(recorder.clone())
+            .with_refresh_margin(Duration::ZERO)
+            .with_refresh_hook(move |event| hook_events.lock().unwrap().push(event.reason))
+            .build()?;
+        let logins = || {
+            recorder
+                .cassette()
+                .interactions
+                .iter()
+                .filter(|interaction| interaction.request.uri == "/token")
+                .count()
+        };
+
+        let created = User::default().with_domain("mock.tm");
+        client.create_account(&created)?;
+        server.set_token_lifetime(Duration::from_secs(1));
+        let user = client.login(&created.address(), &created.password)?;
+        assert_eq!(logins(), 1);
+
+        // Expired on both ends, so refreshed before the request is sent
+        let expiry = token::expiry(&user.email_token).unwrap();
+        while SystemTime::now() <= expiry + Duration::from_millis(50) {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        server.set_token_lifetime(Duration::from_secs(3600));
+        assert_eq!(client.me(&user)?.address, created.address().to_lowercase());
+        assert_eq!(logins(), 2);
+        assert_eq!(*events.lock().unwrap(), vec![RefreshReason::Expiring]);
+
+        // Looks fine locally but is turned away by the api, so refreshed and sent again
+        let other = User::default().with_domain("mock.tm");
+        client.create_account(&other)?;
+        let rejected = crate::update_token(&other, "not a jwt");
+        assert_eq!(client.me(&rejected)?.address, other.address().to_lowercase());
+        assert_eq!(logins(), 3);
+        assert_eq!(*events.lock().unwrap(), vec![RefreshReason::Expiring, RefreshReason::Rejected]);
+        Ok(())
+    }
+}
@@ -0,0 +1,1044 @@
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+use futures_lite::AsyncWrite;
+use rand::seq::SliceRandom;
+use serde::de::DeserializeOwned;
+
+use crate::accounts::{self, Account};
+use crate::auth::{Auth, RefreshEvent, RefreshHook, RefreshReason};
+use crate::domains::{self, Domain, DomainCache};
+use crate::error::{ApiError, MailTmError};
+use crate::events::{self, Subscription};
+use crate::http::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use crate::http::request::Builder;
+use crate::http::{self, HeaderMap, Method, Request, Response, StatusCode, Uri};
+#[cfg(feature = "async")]
+use crate::hydra;
+use crate::hydra::{HydraCollection, HydraIter};
+use crate::messages::{self, Attachment, Message};
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::retry::{self, RetryPolicy};
+use crate::sources;
+use crate::token::{self, Token};
+#[cfg(feature = "async")]
+use crate::transport::AsyncBody;
+use crate::transport::{self, Body, Transport};
+use crate::user::User;
+use crate::{API_URLS, API_URL_ENV, MERCURE_URL};
+
+/// A reusable Mail-TM client
+///
+/// Owns a single [`Transport`] together with the base url and default headers, so one client can
+/// be shared across any number of calls. Cloning is cheap and every clone shares the same
+/// transport, and with it the same connection pool.
+///
+/// Every free function in the crate root is available as a method here.
+///
+/// The client also keeps track of the tokens of the users it is handed. A token about to expire is
+/// refreshed through `/token` with the user's password, and a request rejected with a 401 is retried
+/// once with a fresh token, see [`crate::auth`]. Rate limited requests and server errors can be
+/// retried with backoff by giving the client a [`RetryPolicy`], and an optional [`RateLimiter`] keeps
+/// requests under the api's limits in the first place.
+///
+/// # Example
+/// ```no_run
+/// use mail_tm_rs::MailTmClient;
+/// use mail_tm_rs::user::User;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let client = MailTmClient::new()?;
+///     let user = User::default().with_domain(&client.domains()?.any_active().ok_or("no active domains")?.domain);
+///     let account = client.create_account(&user)?;
+///     let user = mail_tm_rs::update_token(&user, &client.token(&user)?.token);
+///     let messages = client.list_messages(&user, None)?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct MailTmClient {
+    transport: Arc<dyn Transport>,
+    base_url: String,
+    mercure_url: String,
+    headers: HeaderMap,
+    auth: Arc<Auth>,
+    domain_cache: Option<Arc<DomainCache>>,
+    retry: RetryPolicy,
+    rate_limiter: Option<RateLimiter>,
+}
+
+impl MailTmClient {
+    /// Builds a client with the default headers and base url
+    pub fn new() -> Result<MailTmClient, MailTmError> {
+        MailTmClientBuilder::new()?.build()
+    }
+
+    /// Starts building a client with custom configuration
+    pub fn builder() -> Result<MailTmClientBuilder, MailTmError> {
+        MailTmClientBuilder::new()
+    }
+
+    /// The base url every request is made against
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// The Mercure hub used for real time updates
+    pub fn mercure_url(&self) -> &str {
+        &self.mercure_url
+    }
+
+    /// The headers sent along with every request
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// See [`crate::create_account`]
+    pub fn create_account(&self, user: &User) -> Result<Account, MailTmError> {
+        accounts::create(self, user)
+    }
+
+    /// See [`crate::get_account`]
+    pub fn get_account(&self, user: &User, id: &str) -> Result<Account, MailTmError> {
+        self.authorized(user, |token| accounts::get(self, token, id))
+    }
+
+    /// See [`crate::delete_account`]
+    ///
+    /// Also forgets the user's token, see [`MailTmClient::forget`].
+    pub fn delete_account(&self, user: &User, id: &str) -> Result<(), MailTmError> {
+        self.authorized(user, |token| accounts::delete(self, token, id))?;
+        self.forget(user);
+        Ok(())
+    }
+
+    /// See [`crate::me`]
+    pub fn me(&self, user: &User) -> Result<Account, MailTmError> {
+        self.authorized(user, |token| accounts::me(self, token))
+    }
+
+    /// See [`crate::domains()`]
+    pub fn domains(&self) -> Result<HydraCollection<Domain>, MailTmError> {
+        domains::domains(self)
+    }
+
+    /// See [`crate::list_domains`]
+    pub fn list_domains(&self, page: usize) -> Result<HydraCollection<Domain>, MailTmError> {
+        domains::list(self, page)
+    }
+
+    /// See [`crate::get_domain`]
+    pub fn get_domain(&self, id: &str) -> Result<Domain, MailTmError> {
+        domains::get(self, id)
+    }
+
+    /// See [`crate::find_domain`]
+    pub fn find_domain(&self, name: &str) -> Result<Option<Domain>, MailTmError> {
+        domains::find(self, name)
+    }
+
+    /// The domains currently accepting new accounts
+    pub fn active_domains(&self) -> Result<Vec<Domain>, MailTmError> {
+        Ok(self.domains()?.active())
+    }
+
+    /// Drops the cached domain listing so the next call fetches it again
+    ///
+    /// Does nothing unless the client was built with [`MailTmClientBuilder::with_domain_cache`].
+    pub fn invalidate_domains(&self) {
+        if let Some(cache) = &self.domain_cache {
+            cache.invalidate();
+        }
+    }
+
+    /// See [`crate::iter_domains`]
+    pub fn iter_domains(&self) -> HydraIter<Domain> {
+        self.paginate(None, "/domains")
+    }
+
+    /// See [`crate::iter_messages`]
+    pub fn iter_messages(&self, user: &User) -> HydraIter<Message> {
+        self.paginate(Some(user), "/messages")
+    }
+
+    /// Walks any hydra collection starting at `path`, authenticating as `user` when given one
+    pub fn paginate<T: DeserializeOwned>(&self, user: Option<&User>, path: &str) -> HydraIter<T> {
+        HydraIter::new(self, user, path)
+    }
+
+    /// Fetches a single page of any hydra collection, such as a path built from a [`crate::hydra::SearchQuery`]
+    pub fn collection<T: DeserializeOwned>(&self, user: Option<&User>, path: &str) -> Result<HydraCollection<T>, MailTmError> {
+        match user {
+            Some(user) => self.authorized(user, |token| self.call(self.request(Method::GET, path, Some(token)).body(String::new())?)),
+            None => self.call(self.request(Method::GET, path, None).body(String::new())?),
+        }
+    }
+
+    /// Async version of [`MailTmClient::collection`]
+    #[cfg(feature = "async")]
+    pub async fn collection_async<T: DeserializeOwned>(&self, user: Option<&User>, path: &str) -> Result<HydraCollection<T>, MailTmError> {
+        hydra::fetch_async(self, user, path).await
+    }
+
+    /// See [`crate::list_messages`]
+    pub fn list_messages(&self, user: &User, page: Option<usize>) -> Result<HydraCollection<Message>, MailTmError> {
+        self.authorized(user, |token| messages::messages(self, token, page))
+    }
+
+    /// See [`crate::get_message`]
+    pub fn get_message(&self, user: &User, id: &str) -> Result<Message, MailTmError> {
+        self.authorized(user, |token| messages::get(self, token, id))
+    }
+
+    /// See [`crate::delete_message`]
+    pub fn delete_message(&self, user: &User, id: &str) -> Result<(), MailTmError> {
+        self.authorized(user, |token| messages::delete(self, token, id))
+    }
+
+    /// See [`crate::mark_seen`]
+    pub fn mark_seen(&self, user: &User, id: &str, seen: bool) -> Result<Message, MailTmError> {
+        self.authorized(user, |token| messages::patch(self, token, id, seen))
+    }
+
+    /// See [`crate::get_message_source`]
+    pub fn get_message_source(&self, user: &User, id: &str) -> Result<String, MailTmError> {
+        self.authorized(user, |token| Ok(sources::get(self, token, id)?.data))
+    }
+
+    /// See [`crate::download_message_source`]
+    pub fn download_message_source<W: Write>(&self, user: &User, message: &Message, mut writer: W) -> Result<u64, MailTmError> {
+        self.authorized(user, |token| sources::download(self, token, &message.download_url, &mut writer))
+    }
+
+    /// See [`crate::download_attachment`]
+    pub fn download_attachment<W: Write>(&self, user: &User, attachment: &Attachment, mut writer: W) -> Result<u64, MailTmError> {
+        self.authorized(user, |token| messages::download_attachment(self, token, attachment, &mut writer))
+    }
+
+    /// See [`crate::get_attachment`]
+    pub fn get_attachment(&self, user: &User, attachment: &Attachment) -> Result<Vec<u8>, MailTmError> {
+        let mut buffer = Vec::with_capacity(attachment.size.clamp(0, MAX_PREALLOCATION) as usize);
+        self.download_attachment(user, attachment, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// See [`crate::wait_for_message`]
+    pub fn wait_for_message<P: FnMut(&Message) -> bool>(&self, user: &User, predicate: P, timeout: Duration) -> Result<Message, MailTmError> {
+        messages::wait_for(self, user, predicate, timeout)
+    }
+
+    /// See [`crate::subscribe`]
+    pub fn subscribe(&self, user: &User, account_id: &str) -> Subscription {
+        events::subscribe(self, user, account_id)
+    }
+
+    /// Drops the token the client refreshed for the user
+    ///
+    /// The client keeps the freshest token of every user it refreshes one for. Forgetting users that
+    /// are no longer needed keeps a long lived client from holding on to them.
+    pub fn forget(&self, user: &User) {
+        self.auth.forget(user);
+    }
+
+    /// See [`crate::login`]
+    pub fn login(&self, address: &str, password: &str) -> Result<User, MailTmError> {
+        token::login(self, address, password)
+    }
+
+    /// See [`crate::token()`]
+    pub fn token(&self, user: &User) -> Result<Token, MailTmError> {
+        token::token(self, user)
+    }
+
+    /// Async version of [`MailTmClient::create_account`]
+    #[cfg(feature = "async")]
+    pub async fn create_account_async(&self, user: &User) -> Result<Account, MailTmError> {
+        accounts::create_async(self, user).await
+    }
+
+    /// Async version of [`MailTmClient::get_account`]
+    #[cfg(feature = "async")]
+    pub async fn get_account_async(&self, user: &User, id: &str) -> Result<Account, MailTmError> {
+        self.authorized_async(user, |token| async move { accounts::get_async(self, &token, id).await }).await
+    }
+
+    /// Async version of [`MailTmClient::delete_account`]
+    #[cfg(feature = "async")]
+    pub async fn delete_account_async(&self, user: &User, id: &str) -> Result<(), MailTmError> {
+        self.authorized_async(user, |token| async move { accounts::delete_async(self, &token, id).await }).await?;
+        self.forget(user);
+        Ok(())
+    }
+
+    /// Async version of [`MailTmClient::me`]
+    #[cfg(feature = "async")]
+    pub async fn me_async(&self, user: &User) -> Result<Account, MailTmError> {
+        self.authorized_async(user, |token| async move { accounts::me_async(self, &token).await }).await
+    }
+
+    /// Async version of [`MailTmClient::domains`]
+    #[cfg(feature = "async")]
+    pub async fn domains_async(&self) -> Result<HydraCollection<Domain>, MailTmError> {
+        domains::domains_async(self).await
+    }
+
+    /// Async version of [`MailTmClient::list_domains`]
+    #[cfg(feature = "async")]
+    pub async fn list_domains_async(&self, page: usize) -> Result<HydraCollection<Domain>, MailTmError> {
+        domains::list_async(self, page).await
+    }
+
+    /// Async version of [`MailTmClient::get_domain`]
+    #[cfg(feature = "async")]
+    pub async fn get_domain_async(&self, id: &str) -> Result<Domain, MailTmError> {
+        domains::get_async(self, id).await
+    }
+
+    /// Async version of [`MailTmClient::find_domain`]
+    #[cfg(feature = "async")]
+    pub async fn find_domain_async(&self, name: &str) -> Result<Option<Domain>, MailTmError> {
+        domains::find_async(self, name).await
+    }
+
+    /// Async version of [`MailTmClient::active_domains`]
+    #[cfg(feature = "async")]
+    pub async fn active_domains_async(&self) -> Result<Vec<Domain>, MailTmError> {
+        Ok(self.domains_async().await?.active())
+    }
+
+    /// Async version of [`MailTmClient::list_messages`]
+    #[cfg(feature = "async")]
+    pub async fn list_messages_async(&self, user: &User, page: Option<usize>) -> Result<HydraCollection<Message>, MailTmError> {
+        self.authorized_async(user, |token| async move { messages::messages_async(self, &token, page).await }).await
+    }
+
+    /// Async version of [`MailTmClient::get_message`]
+    #[cfg(feature = "async")]
+    pub async fn get_message_async(&self, user: &User, id: &str) -> Result<Message, MailTmError> {
+        self.authorized_async(user, |token| async move { messages::get_async(self, &token, id).await }).await
+    }
+
+    /// Async version of [`MailTmClient::delete_message`]
+    #[cfg(feature = "async")]
+    pub async fn delete_message_async(&self, user: &User, id: &str) -> Result<(), MailTmError> {
+        self.authorized_async(user, |token| async move { messages::delete_async(self, &token, id).await }).await
+    }
+
+    /// Async version of [`MailTmClient::mark_seen`]
+    #[cfg(feature = "async")]
+    pub async fn mark_seen_async(&self, user: &User, id: &str, seen: bool) -> Result<Message, MailTmError> {
+        self.authorized_async(user, |token| async move { messages::patch_async(self, &token, id, seen).await }).await
+    }
+
+    /// Async version of [`MailTmClient::get_message_source`]
+    #[cfg(feature = "async")]
+    pub async fn get_message_source_async(&self, user: &User, id: &str) -> Result<String, MailTmError> {
+        self.authorized_async(user, |token| async move { Ok(sources::get_async(self, &token, id).await?.data) }).await
+    }
+
+    /// Async version of [`MailTmClient::download_message_source`]
+    #[cfg(feature = "async")]
+    pub async fn download_message_source_async<W: AsyncWrite + Unpin>(&self, user: &User, message: &Message, mut writer: W) -> Result<u64, MailTmError> {
+        // Not through authorized_async, its callback cannot lend out the writer more than once
+        let token = self.auth_token_async(user).await?;
+        let result = sources::download_async(self, &token, &message.download_url, &mut writer).await;
+        if !self.token_rejected(user, &result) {
+            return result;
+        }
+        let token = self.refresh_async(user, RefreshReason::Rejected).await?;
+        sources::download_async(self, &token, &message.download_url, &mut writer).await
+    }
+
+    /// Async version of [`MailTmClient::download_attachment`]
+    #[cfg(feature = "async")]
+    pub async fn download_attachment_async<W: AsyncWrite + Unpin>(&self, user: &User, attachment: &Attachment, mut writer: W) -> Result<u64, MailTmError> {
+        let token = self.auth_token_async(user).await?;
+        let result = messages::download_attachment_async(self, &token, attachment, &mut writer).await;
+        if !self.token_rejected(user, &result) {
+            return result;
+        }
+        let token = self.refresh_async(user, RefreshReason::Rejected).await?;
+        messages::download_attachment_async(self, &token, attachment, &mut writer).await
+    }
+
+    /// Async version of [`MailTmClient::get_attachment`]
+    #[cfg(feature = "async")]
+    pub async fn get_attachment_async(&self, user: &User, attachment: &Attachment) -> Result<Vec<u8>, MailTmError> {
+        let mut buffer = Vec::with_capacity(attachment.size.clamp(0, MAX_PREALLOCATION) as usize);
+        self.download_attachment_async(user, attachment, &mut buffer).await?;
+        Ok(buffer)
+    }
+
+    /// Async version of [`MailTmClient::wait_for_message`]
+    #[cfg(feature = "async")]
+    pub async fn wait_for_message_async<P: FnMut(&Message) -> bool>(&self, user: &User, predicate: P, timeout: Duration) -> Result<Message, MailTmError> {
+        messages::wait_for_async(self, user, predicate, timeout).await
+    }
+
+    /// Async version of [`MailTmClient::subscribe`], yielding events through a [`futures_lite::Stream`]
+    #[cfg(feature = "async")]
+    pub fn subscribe_async(&self, user: &User, account_id: &str) -> impl futures_lite::Stream<Item = Result<events::Event, MailTmError>> + Send + 'static {
+        events::subscribe_async(self, user, account_id)
+    }
+
+    /// Async version of [`MailTmClient::login`]
+    #[cfg(feature = "async")]
+    pub async fn login_async(&self, address: &str, password: &str) -> Result<User, MailTmError> {
+        token::login_async(self, address, password).await
+    }
+
+    /// Async version of [`MailTmClient::token`]
+    #[cfg(feature = "async")]
+    pub async fn token_async(&self, user: &User) -> Result<Token, MailTmError> {
+        token::token_async(self, user).await
+    }
+
+    pub(crate) fn domain_cache(&self) -> Option<&DomainCache> {
+        self.domain_cache.as_deref()
+    }
+
+    /// Joins a path onto the base url, absolute urls are left untouched
+    pub(crate) fn url(&self, path: &str) -> String {
+        if path.starts_with("http://") || path.starts_with("https://") {
+            path.to_string()
+        } else {
+            format!("{}{}", self.base_url, path)
+        }
+    }
+
+    /// Builds a request, with the token only if the url points at the api or the Mercure hub
+    ///
+    /// Urls handed out by the server, such as download urls, may be absolute. The token is kept
+    /// from any other host so it cannot leak to wherever such a url points.
+    pub(crate) fn request(&self, method: Method, path: &str, token: Option<&str>) -> Builder {
+        let url = self.url(path);
+        let token = token.filter(|_| {
+            let trusted = same_origin(&url, &self.base_url) || same_origin(&url, &self.mercure_url);
+            if !trusted {
+                log::warn!("Not sending the token to {}, it is not on {}", url, self.base_url);
+            }
+            trusted
+        });
+        let builder = Request::builder()
+            .method(method)
+            .uri(url);
+        match token {
+            Some(token) => builder.header(AUTHORIZATION, format!("Bearer {}", token)),
+            None => builder,
+        }
+    }
+
+    /// The freshest token known for the user, refreshed first if it is about to expire
+    pub(crate) fn auth_token(&self, user: &User) -> Result<String, MailTmError> {
+        let token = self.auth.current(user);
+        if self.auth.needs_refresh(user, &token) {
+            return self.refresh(user, RefreshReason::Expiring);
+        }
+        Ok(token)
+    }
+
+    fn refresh(&self, user: &User, reason: RefreshReason) -> Result<String, MailTmError> {
+        log::debug!("Refreshing token for {}: {:?}", user.address(), reason);
+        let token = token::token(self, user)?.token;
+        self.auth.store(user, &token, reason);
+        Ok(token)
+    }
+
+    /// Whether the api rejected the token and a fresh one can be fetched to try again with
+    fn token_rejected<T>(&self, user: &User, result: &Result<T, MailTmError>) -> bool {
+        matches!(result, Err(MailTmError::Unauthorized(_)) | Err(MailTmError::TokenExpired(_))) && self.auth.can_refresh(user)
+    }
+
+    /// Runs `call` with the user's token, refreshing and retrying once if the token is rejected
+    pub(crate) fn authorized<T, F>(&self, user: &User, mut call: F) -> Result<T, MailTmError>
+        where F: FnMut(&str) -> Result<T, MailTmError> {
+        let token = self.auth_token(user)?;
+        let result = call(&token);
+        if !self.token_rejected(user, &result) {
+            return result;
+        }
+        let token = self.refresh(user, RefreshReason::Rejected)?;
+        call(&token)
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) async fn auth_token_async(&self, user: &User) -> Result<String, MailTmError> {
+        let token = self.auth.current(user);
+        if self.auth.needs_refresh(user, &token) {
+            return self.refresh_async(user, RefreshReason::Expiring).await;
+        }
+        Ok(token)
+    }
+
+    #[cfg(feature = "async")]
+    async fn refresh_async(&self, user: &User, reason: RefreshReason) -> Result<String, MailTmError> {
+        log::debug!("Refreshing token for {}: {:?}", user.address(), reason);
+        let token = token::token_async(self, user).await?.token;
+        self.auth.store(user, &token, reason);
+        Ok(token)
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) async fn authorized_async<T, F, Fut>(&self, user: &User, call: F) -> Result<T, MailTmError>
+        where F: Fn(String) -> Fut,
+              Fut: std::future::Future<Output = Result<T, MailTmError>> {
+        let token = self.auth_token_async(user).await?;
+        let result = call(token).await;
+        if !self.token_rejected(user, &result) {
+            return result;
+        }
+        let token = self.refresh_async(user, RefreshReason::Rejected).await?;
+        call(token).await
+    }
+
+    /// Fills in the default headers the request does not set itself
+    fn prepare(&self, mut request: Request<String>) -> Request<String> {
+        let headers = request.headers_mut();
+        for name in self.headers.keys() {
+            if !headers.contains_key(name) {
+                for value in self.headers.get_all(name) {
+                    headers.append(name.clone(), value.clone());
+                }
+            }
+        }
+        request
+    }
+
+    /// Sends the request, again and again as long as the retry policy allows
+    fn dispatch<B, F>(&self, request: Request<String>, mut send: F) -> Result<(u32, Response<B>), MailTmError>
+        where F: FnMut(Request<String>) -> Result<Response<B>, MailTmError> {
+        let request = self.prepare(request);
+        let mut attempt = 1;
+        loop {
+            if let Some(wait) = self.rate_limit_wait(&request) {
+                thread::sleep(wait);
+            }
+            let result = send(retry::copy_request(&request));
+            match self.retry_delay(&request, attempt, &result) {
+                Some(delay) => thread::sleep(delay),
+                None => return result.map(|response| (attempt, response)),
+            }
+            attempt += 1;
+        }
+    }
+
+    /// How long the rate limiter holds the request back, if at all
+    fn rate_limit_wait(&self, request: &Request<String>) -> Option<Duration> {
+        let host = request.uri().host()?;
+        let wait = self.rate_limiter.as_ref()?.acquire(host);
+        if wait.is_zero() {
+            return None;
+        }
+        log::trace!("Rate limiting {} {} for {:?}", request.method(), request.uri(), wait);
+        Some(wait)
+    }
+
+    fn retry_delay<B>(&self, request: &Request<String>, attempt: u32, result: &Result<Response<B>, MailTmError>) -> Option<Duration> {
+        let (status, headers) = match result {
+            Ok(response) => (Some(response.status()), Some(response.headers())),
+            Err(MailTmError::Transport(_)) => (None, None),
+            Err(_) => return None,
+        };
+        let delay = self
+            .retry
+            .next_delay(request.method(), attempt, status, || retry::retry_after(headers?))?;
+        match status {
+            Some(status) => log::debug!("Retrying {} {} in {:?} after a {}", request.method(), request.uri(), delay, status),
+            None => log::debug!("Retrying {} {} in {:?} after it failed", request.method(), request.uri(), delay),
+        }
+        Some(delay)
+    }
+
+    /// Fails with [`MailTmError::RateLimitExceeded`] if the request was still rate limited when the
+    /// retry policy gave up
+    fn check_rate_limit(&self, attempts: u32, status: StatusCode, headers: &HeaderMap, body: &str) -> Result<(), MailTmError> {
+        if status == StatusCode::TOO_MANY_REQUESTS && self.retry.max_attempts() > 1 {
+            return Err(MailTmError::RateLimitExceeded {
+                attempts,
+                retry_after: retry::retry_after(headers),
+                error: ApiError::parse(status.as_u16(), body),
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn send(&self, request: Request<String>) -> Result<(StatusCode, String), MailTmError> {
+        let (attempts, response) = self.dispatch(request, |request| self.transport.send(request))?;
+        let body = String::from_utf8_lossy(response.body()).into_owned();
+        self.check_rate_limit(attempts, response.status(), response.headers(), &body)?;
+        Ok((response.status(), body))
+    }
+
+    /// Sends a request and returns its body, failing on a non success status
+    pub(crate) fn execute(&self, request: Request<String>) -> Result<String, MailTmError> {
+        let uri = request.uri().to_string();
+        let (code, body) = self.send(request)?;
+        http::check_response_status(&code, &body)?;
+        log::trace!("Response from {}: {}", uri, body);
+        Ok(body)
+    }
+
+    /// Sends a request and deserializes its body
+    pub(crate) fn call<T: DeserializeOwned>(&self, request: Request<String>) -> Result<T, MailTmError> {
+        let body = self.execute(request)?;
+        serde_json::from_str(&body).map_err(|err| MailTmError::decode(err, &body))
+    }
+
+    /// Sends a request and hands back the response with its body unread
+    pub(crate) fn open(&self, request: Request<String>) -> Result<Response<Body>, MailTmError> {
+        let (attempts, mut response) = self.dispatch(request, |request| self.transport.open(request))?;
+        let code = response.status();
+        if !code.is_success() {
+            let mut body = String::new();
+            response.body_mut().read_to_string(&mut body)?;
+            self.check_rate_limit(attempts, code, response.headers(), &body)?;
+            http::check_response_status(&code, &body)?;
+        }
+        Ok(response)
+    }
+
+    /// Sends a request and streams its body into `writer`, returning the number of bytes written
+    pub(crate) fn download<W: Write>(&self, request: Request<String>, mut writer: W) -> Result<u64, MailTmError> {
+        let mut response = self.open(request)?;
+        Ok(io::copy(response.body_mut(), &mut writer)?)
+    }
+
+    #[cfg(feature = "async")]
+    async fn dispatch_async<B, F, Fut>(&self, request: Request<String>, send: F) -> Result<(u32, Response<B>), MailTmError>
+        where F: Fn(Request<String>) -> Fut,
+              Fut: std::future::Future<Output = Result<Response<B>, MailTmError>> {
+        let request = self.prepare(request);
+        let mut attempt = 1;
+        loop {
+            if let Some(wait) = self.rate_limit_wait(&request) {
+                futures_timer::Delay::new(wait).await;
+            }
+            let result = send(retry::copy_request(&request)).await;
+            match self.retry_delay(&request, attempt, &result) {
+                Some(delay) => futures_timer::Delay::new(delay).await,
+                None => return result.map(|response| (attempt, response)),
+            }
+            attempt += 1;
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) async fn send_async(&self, request: Request<String>) -> Result<(StatusCode, String), MailTmError> {
+        let (attempts, response) = self.dispatch_async(request, |request| self.transport.send_async(request)).await?;
+        let body = String::from_utf8_lossy(response.body()).into_owned();
+        self.check_rate_limit(attempts, response.status(), response.headers(), &body)?;
+        Ok((response.status(), body))
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) async fn execute_async(&self, request: Request<String>) -> Result<String, MailTmError> {
+        let uri = request.uri().to_string();
+        let (code, body) = self.send_async(request).await?;
+        http::check_response_status(&code, &body)?;
+        log::trace!("Response from {}: {}", uri, body);
+        Ok(body)
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) async fn open_async(&self, request: Request<String>) -> Result<Response<AsyncBody>, MailTmError> {
+        use futures_lite::AsyncReadExt;
+
+        let (attempts, mut response) = self.dispatch_async(request, |request| self.transport.open_async(request)).await?;
+        let code = response.status();
+        if !code.is_success() {
+            let mut body = String::new();
+            response.body_mut().read_to_string(&mut body).await?;
+            self.check_rate_limit(attempts, code, response.headers(), &body)?;
+            http::check_response_status(&code, &body)?;
+        }
+        Ok(response)
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) async fn download_async<W: AsyncWrite + Unpin>(&self, request: Request<String>, writer: W) -> Result<u64, MailTmError> {
+        let response = self.open_async(request).await?;
+        Ok(futures_lite::io::copy(response.into_body(), writer).await?)
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) async fn call_async<T: DeserializeOwned>(&self, request: Request<String>) -> Result<T, MailTmError> {
+        let body = self.execute_async(request).await?;
+        serde_json::from_str(&body).map_err(|err| MailTmError::decode(err, &body))
+    }
+}
+
+/// Whether both urls have the same scheme, host and port
+fn same_origin(url: &str, other: &str) -> bool {
+    fn origin(url: &str) -> Option<(String, String, u16)> {
+        let uri: Uri = url.parse().ok()?;
+        let scheme = uri.scheme_str()?.to_ascii_lowercase();
+        let port = uri.port_u16().or(match scheme.as_str() {
+            "http" => Some(80),
+            "https" => Some(443),
+            _ => None,
+        })?;
+        Some((scheme, uri.host()?.to_ascii_lowercase(), port))
+    }
+    match (origin(url), origin(other)) {
+        (Some(ours), Some(theirs)) => ours == theirs,
+        _ => false,
+    }
+}
+
+/// The most memory reserved up front for an attachment, whatever size it claims to be
+const MAX_PREALLOCATION: i64 = 16 * 1024 * 1024;
+
+const DEFAULT_REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+/// How a client picks the base url it talks to
+///
+/// The url is resolved once when the client is built, so tokens minted by a client are always used
+/// against the same host.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub enum BaseUrl {
+    /// Read from the [`API_URL_ENV`] environment variable, falling back to the first of [`API_URLS`]
+    #[default]
+    Default,
+    /// Always use the given url
+    Fixed(String),
+    /// Pick one of [`API_URLS`] at random
+    Random,
+}
+
+impl BaseUrl {
+    pub fn resolve(&self) -> String {
+        let url = match self {
+            BaseUrl::Default => std::env::var(API_URL_ENV)
+                .ok()
+                .filter(|url| !url.trim().is_empty())
+                .unwrap_or_else(|| API_URLS[0].to_string()),
+            BaseUrl::Fixed(url) => url.to_string(),
+            BaseUrl::Random => API_URLS
+                .choose(&mut rand::thread_rng())
+                .unwrap()
+                .to_string(),
+        };
+        url.trim().trim_end_matches('/').to_string()
+    }
+}
+
+/// Builder for a [`MailTmClient`]
+///
+/// Starts out with the headers from [`http::get_headers`] and [`BaseUrl::Default`].
+pub struct MailTmClientBuilder {
+    base_url: BaseUrl,
+    mercure_url: String,
+    headers: HeaderMap,
+    token_refresh: bool,
+    refresh_margin: Duration,
+    refresh_hook: Option<RefreshHook>,
+    domain_cache: Option<Duration>,
+    transport: Option<Arc<dyn Transport>>,
+    retry: RetryPolicy,
+    rate_limiter: Option<RateLimiter>,
+}
+
+impl MailTmClientBuilder {
+    pub fn new() -> Result<MailTmClientBuilder, MailTmError> {
+        Ok(MailTmClientBuilder {
+            base_url: BaseUrl::Default,
+            mercure_url: MERCURE_URL.to_string(),
+            headers: http::get_headers()?,
+            token_refresh: true,
+            refresh_margin: DEFAULT_REFRESH_MARGIN,
+            refresh_hook: None,
+            domain_cache: None,
+            transport: None,
+            retry: RetryPolicy::none(),
+            rate_limiter: None,
+        })
+    }
+
+    /// Sets the base url, such as `https://api.mail.tm`
+    pub fn with_base_url(self, base_url: &str) -> MailTmClientBuilder {
+        self.with_base_url_strategy(BaseUrl::Fixed(base_url.to_string()))
+    }
+
+    /// Picks one of the public api hosts at random when the client is built
+    pub fn with_random_base_url(self) -> MailTmClientBuilder {
+        self.with_base_url_strategy(BaseUrl::Random)
+    }
+
+    pub fn with_base_url_strategy(self, base_url: BaseUrl) -> MailTmClientBuilder {
+        MailTmClientBuilder {
+            base_url,
+            ..self
+        }
+    }
+
+    /// Sets the Mercure hub used by [`MailTmClient::subscribe`]
+    pub fn with_mercure_url(self, mercure_url: &str) -> MailTmClientBuilder {
+        MailTmClientBuilder {
+            mercure_url: mercure_url.trim_end_matches('/').to_string(),
+            ..self
+        }
+    }
+
+    /// Turns automatic token refreshing on or off, it is on by default
+    pub fn with_token_refresh(self, token_refresh: bool) -> MailTmClientBuilder {
+        MailTmClientBuilder {
+            token_refresh,
+            ..self
+        }
+    }
+
+    /// How long before a token expires it gets refreshed, defaults to 30 seconds
+    pub fn with_refresh_margin(self, refresh_margin: Duration) -> MailTmClientBuilder {
+        MailTmClientBuilder {
+            refresh_margin,
+            ..self
+        }
+    }
+
+    /// Calls `hook` every time a token is refreshed, for instance to persist the new token
+    pub fn with_refresh_hook<F>(self, hook: F) -> MailTmClientBuilder
+        where F: Fn(&RefreshEvent) + Send + Sync + 'static {
+        MailTmClientBuilder {
+            refresh_hook: Some(Arc::new(hook)),
+            ..self
+        }
+    }
+
+    /// Caches the `/domains` listing for `ttl`, it is fetched on every call by default
+    ///
+    /// The cache is shared by every clone of the client, see [`MailTmClient::invalidate_domains`].
+    pub fn with_domain_cache(self, ttl: Duration) -> MailTmClientBuilder {
+        MailTmClientBuilder {
+            domain_cache: Some(ttl),
+            ..self
+        }
+    }
+
+    /// Sends requests through `transport` instead of the default backend, see [`crate::transport`]
+    pub fn with_transport<T: Transport + 'static>(self, transport: T) -> MailTmClientBuilder {
+        MailTmClientBuilder {
+            transport: Some(Arc::new(transport)),
+            ..self
+        }
+    }
+
+    /// Sets how rate limited and failed requests are retried, see [`crate::retry`]
+    ///
+    /// Requests are sent once unless a policy is set, [`RetryPolicy::default`] is a good start.
+    pub fn with_retry_policy(self, retry: RetryPolicy) -> MailTmClientBuilder {
+        MailTmClientBuilder {
+            retry,
+            ..self
+        }
+    }
+
+    /// Holds requests back to stay under `limit` on every host, see [`crate::rate_limit`]
+    ///
+    /// Requests are not rate limited by default.
+    pub fn with_rate_limit(self, limit: RateLimit) -> MailTmClientBuilder {
+        self.with_rate_limiter(RateLimiter::default().with_limit(limit))
+    }
+
+    /// Holds requests back according to `rate_limiter`, which may be shared with other clients
+    pub fn with_rate_limiter(self, rate_limiter: RateLimiter) -> MailTmClientBuilder {
+        MailTmClientBuilder {
+            rate_limiter: Some(rate_limiter),
+            ..self
+        }
+    }
+
+    /// Adds or replaces a header sent with every request
+    pub fn with_header(mut self, name: &str, value: &str) -> Result<MailTmClientBuilder, MailTmError> {
+        self.headers
+            .insert(name.parse::<HeaderName>()?, value.parse::<HeaderValue>()?);
+        Ok(self)
+    }
+
+    pub fn build(self) -> Result<MailTmClient, MailTmError> {
+        let transport = match self.transport {
+            Some(transport) => transport,
+            None => transport::default_transport()?,
+        };
+        Ok(MailTmClient {
+            transport,
+            base_url: self.base_url.resolve(),
+            mercure_url: self.mercure_url,
+            headers: self.headers,
+            auth: Arc::new(Auth::new(self.token_refresh, self.refresh_margin, self.refresh_hook)),
+            domain_cache: self.domain_cache.map(|ttl| Arc::new(DomainCache::new(ttl))),
+            retry: self.retry,
+            rate_limiter: self.rate_limiter,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::header::CONTENT_TYPE;
+    use crate::transport::MemoryTransport;
+
+    #[test]
+    fn test_builder() -> Result<(), MailTmError> {
+        let client = MailTmClient::builder()?
+            .with_base_url("http://localhost:8080/")
+            .with_header("X-Test", "yes")?
+            .build()?;
+
+        assert_eq!(client.base_url(), "http://localhost:8080");
+        assert_eq!(client.headers().get("X-Test").unwrap(), "yes");
+
+        let request = client
+            .request(Method::GET, "/me", Some("abc"))
+            .body(String::new())?;
+        assert_eq!(request.uri(), "http://localhost:8080/me");
+        assert_eq!(request.headers().get(AUTHORIZATION).unwrap(), "Bearer abc");
+
+        assert_eq!(client.url("/messages/1/download"), "http://localhost:8080/messages/1/download");
+        assert_eq!(client.url("https://api.mail.gw/sources/1"), "https://api.mail.gw/sources/1");
+        assert!(client.domain_cache().is_none());
+
+        let cached = MailTmClient::builder()?.with_domain_cache(Duration::from_secs(300)).build()?;
+        assert!(cached.clone().domain_cache().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transport() -> Result<(), MailTmError> {
+        let transport = MemoryTransport::new();
+        let client = MailTmClient::builder()?
+            .with_base_url("http://localhost")
+            .with_transport(transport.clone())
+            .build()?;
+
+        transport.push_response(200, r#"{"token":"abc","id":"1"}"#);
+        let user = client.login("someone@example.com", "hunter22")?;
+        assert_eq!(user.email_token, "abc");
+
+        let token = transport.last_request().unwrap();
+        assert_eq!(token.method, Method::POST);
+        assert_eq!(token.uri, "http://localhost/token");
+        assert_eq!(token.headers.get("User-Agent").unwrap(), crate::USER_AGENT);
+
+        transport.push_response(200, "{}");
+        assert!(client.mark_seen(&user, "abc", true).is_err());
+        let patch = transport.last_request().unwrap();
+        let content_types: Vec<_> = patch.headers.get_all(CONTENT_TYPE).iter().collect();
+        assert_eq!(content_types, vec!["application/merge-patch+json"]);
+        assert_eq!(patch.headers.get(AUTHORIZATION).unwrap(), "Bearer abc");
+
+        assert!(matches!(client.get_domain("missing"), Err(MailTmError::NotFound(_))));
+        assert_eq!(transport.requests().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_stays_on_api() -> Result<(), MailTmError> {
+        let transport = MemoryTransport::with_handler(|_| Response::new(b"data".to_vec()));
+        let client = MailTmClient::builder()?
+            .with_base_url("https://api.mail.tm")
+            .with_mercure_url("https://mercure.mail.tm/.well-known/mercure")
+            .with_transport(transport.clone())
+            .with_token_refresh(false)
+            .build()?;
+        let user = crate::update_token(&User::new("someone", "hunter22", "example.com"), "tok");
+        let attachment = |download_url: &str| Attachment {
+            download_url: download_url.to_string(),
+            ..Attachment::default()
+        };
+
+        for url in &["/messages/1/attachment/2", "https://API.mail.tm:443/messages/1/attachment/2"] {
+            client.get_attachment(&user, &attachment(url))?;
+            assert_eq!(transport.last_request().unwrap().headers.get(AUTHORIZATION).unwrap(), "Bearer tok");
+        }
+        for url in &["https://evil.example/steal", "http://api.mail.tm/messages/1", "https://api.mail.tm:8443/messages/1", "https://api.mail.tm.evil.example/"] {
+            client.get_attachment(&user, &attachment(url))?;
+            let request = transport.last_request().unwrap();
+            assert_eq!(request.uri, *url);
+            assert!(request.headers.get(AUTHORIZATION).is_none(), "{}", url);
+        }
+
+        let subscribe = client
+            .request(Method::GET, "https://mercure.mail.tm/.well-known/mercure?topic=x", Some("tok"))
+            .body(String::new())?;
+        assert!(subscribe.headers().contains_key(AUTHORIZATION));
+        Ok(())
+    }
+
+    #[test]
+    fn test_attachment_size_hint() -> Result<(), MailTmError> {
+        let transport = MemoryTransport::with_handler(|_| Response::new(b"data".to_vec()));
+        let client = MailTmClient::builder()?
+            .with_base_url("http://localhost")
+            .with_transport(transport)
+            .with_token_refresh(false)
+            .build()?;
+        let user = crate::update_token(&User::new("someone", "hunter22", "example.com"), "tok");
+        let attachment = Attachment {
+            size: i64::MAX,
+            download_url: "/messages/1/attachment/2".to_string(),
+            ..Attachment::default()
+        };
+        assert_eq!(client.get_attachment(&user, &attachment)?, b"data");
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_download_refreshes_token() -> Result<(), MailTmError> {
+        let transport = MemoryTransport::with_handler(|request| {
+            let (status, body) = if request.uri.ends_with("/token") {
+                (200, r#"{"token":"fresh","id":"1"}"#)
+            } else if request.headers.get(AUTHORIZATION).is_some_and(|value| value == "Bearer fresh") {
+                (200, "data")
+            } else {
+                (401, r#"{"code":401,"message":"Expired JWT Token"}"#)
+            };
+            Response::builder().status(status).body(body.as_bytes().to_vec()).unwrap()
+        });
+        let client = MailTmClient::builder()?
+            .with_base_url("http://localhost")
+            .with_transport(transport.clone())
+            .build()?;
+        let user = crate::update_token(&User::new("someone", "hunter22", "example.com"), "stale");
+        let attachment = Attachment {
+            download_url: "/messages/1/attachment/2".to_string(),
+            ..Attachment::default()
+        };
+
+        let data = futures_lite::future::block_on(client.get_attachment_async(&user, &attachment))?;
+        assert_eq!(data, b"data");
+        assert_eq!(transport.requests().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_account_forgets_token() -> Result<(), MailTmError> {
+        let server = crate::mock::MockServer::start()?;
+        let client = server.client()?;
+        let user = User::default().with_domain("mock.tm");
+        let id = client.create_account(&user)?.id.unwrap();
+
+        // Rejected by the api, so the client refreshes and keeps a token for the user
+        let user = crate::update_token(&user, "not a jwt");
+        client.me(&user)?;
+        assert_ne!(client.auth.current(&user), "not a jwt");
+
+        client.delete_account(&user, &id)?;
+        assert_eq!(client.auth.current(&user), "not a jwt");
+        Ok(())
+    }
+
+    #[test]
+    fn test_base_url_strategies() -> Result<(), MailTmError> {
+        assert_eq!(BaseUrl::Fixed(" http://127.0.0.1:1234/ ".to_string()).resolve(), "http://127.0.0.1:1234");
+        assert!(API_URLS.contains(&BaseUrl::Random.resolve().as_str()));
+
+        let client = MailTmClient::builder()?.with_random_base_url().build()?;
+        assert!(API_URLS.contains(&client.base_url()));
+        Ok(())
+    }
+}
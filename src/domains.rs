@@ -1,11 +1,13 @@
-use std::io::Read;
-use anyhow::Error;
-use rand::Rng;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
 use serde::{Deserialize, Serialize};
 
-use crate::{http, MAIL_API_URL};
-use crate::http::Client;
-use crate::hydra::{Search, View, HydraCollection};
+use crate::client::MailTmClient;
+use crate::error::MailTmError;
+use crate::http::{Method, Request};
+use crate::hydra::HydraCollection;
+use crate::timestamp::Timestamp;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -20,46 +22,223 @@ pub struct Domain {
     pub id2: String,
     pub domain: String,
     pub is_active: bool,
-    pub created_at: String,
-    pub updated_at: String,
+    pub created_at: Timestamp,
+    pub updated_at: Timestamp,
 }
 
 impl HydraCollection<Domain> {
     pub fn as_list(&self) -> Vec<String> {
         self.members.iter().map(|domain| domain.domain.to_owned()).collect()
     }
+
+    /// A random domain out of those accepting new accounts
+    pub fn any_active(&self) -> Option<Domain> {
+        self.any_where(|domain| domain.is_active)
+    }
+
+    /// The domains currently accepting new accounts
+    pub fn active(&self) -> Vec<Domain> {
+        self.members
+            .iter()
+            .filter(|domain| domain.is_active)
+            .cloned()
+            .collect()
+    }
 }
 
+/// Remembers the `/domains` listing for a while
+///
+/// Enabled with [`crate::MailTmClientBuilder::with_domain_cache`] and shared by every clone of
+/// the client, so all threads using it see the same listing.
+#[derive(Debug)]
+pub(crate) struct DomainCache {
+    ttl: Duration,
+    entry: RwLock<Option<(Instant, HydraCollection<Domain>)>>,
+}
 
-// TODO memoise me for some time
-pub(crate) fn domains() -> Result<HydraCollection<Domain>, Error> {
-    let client = Client::new()?.build()?;
+impl DomainCache {
+    pub fn new(ttl: Duration) -> DomainCache {
+        DomainCache {
+            ttl,
+            entry: RwLock::new(None),
+        }
+    }
 
-    log::debug!("Getting domains");
+    /// The cached listing, unless it is older than the ttl
+    pub fn get(&self) -> Option<HydraCollection<Domain>> {
+        let entry = self.entry.read().unwrap_or_else(|err| err.into_inner());
+        entry
+            .as_ref()
+            .filter(|(fetched, _)| fetched.elapsed() < self.ttl)
+            .map(|(_, domains)| domains.clone())
+    }
 
-    let mut response = client
-        .get(&format!("{}/domains", MAIL_API_URL.to_owned()))?;
+    pub fn store(&self, domains: &HydraCollection<Domain>) {
+        let mut entry = self.entry.write().unwrap_or_else(|err| err.into_inner());
+        *entry = Some((Instant::now(), domains.clone()));
+    }
 
-    let code = response.status();
+    pub fn invalidate(&self) {
+        let mut entry = self.entry.write().unwrap_or_else(|err| err.into_inner());
+        *entry = None;
+    }
+}
 
-    let response = {
-        let mut buffer = String::new();
-        response.body_mut().read_to_string(&mut buffer)?;
-        buffer
+fn domains_request(client: &MailTmClient, page: Option<usize>) -> Result<Request<String>, MailTmError> {
+    let path = match page {
+        Some(page) => format!("/domains?page={}", page),
+        None => "/domains".to_string(),
     };
 
-    http::check_response_status(&code, &response)?;
+    Ok(client
+        .request(Method::GET, &path, None)
+        .body(String::new())?)
+}
+
+fn get_request(client: &MailTmClient, id: &str) -> Result<Request<String>, MailTmError> {
+    Ok(client
+        .request(Method::GET, &format!("/domains/{}", id), None)
+        .body(String::new())?)
+}
+
+fn matches(domain: &Domain, name: &str) -> bool {
+    domain.domain.eq_ignore_ascii_case(name.trim().trim_start_matches('@'))
+}
+
+pub(crate) fn domains(client: &MailTmClient) -> Result<HydraCollection<Domain>, MailTmError> {
+    if let Some(domains) = client.domain_cache().and_then(DomainCache::get) {
+        log::trace!("Using cached domains");
+        return Ok(domains);
+    }
+
+    log::debug!("Getting domains");
+    let domains = client.call(domains_request(client, None)?)?;
+    if let Some(cache) = client.domain_cache() {
+        cache.store(&domains);
+    }
+    Ok(domains)
+}
+
+pub(crate) fn list(client: &MailTmClient, page: usize) -> Result<HydraCollection<Domain>, MailTmError> {
+    log::debug!("Getting page {} of domains", page);
+    client.call(domains_request(client, Some(page))?)
+}
+
+pub(crate) fn get(client: &MailTmClient, id: &str) -> Result<Domain, MailTmError> {
+    log::debug!("Getting domain with id {}", id);
+    client.call(get_request(client, id)?)
+}
+
+/// Walks the domain listing, starting with the possibly cached first page, until `name` turns up
+pub(crate) fn find(client: &MailTmClient, name: &str) -> Result<Option<Domain>, MailTmError> {
+    log::debug!("Looking for domain {}", name);
+    let mut page = client.domains()?;
+    loop {
+        if let Some(domain) = page.members.iter().find(|domain| matches(domain, name)) {
+            return Ok(Some(domain.clone()));
+        }
+        match page.next_page() {
+            Some(next) => page = client.collection(None, next)?,
+            None => return Ok(None),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+pub(crate) async fn domains_async(client: &MailTmClient) -> Result<HydraCollection<Domain>, MailTmError> {
+    if let Some(domains) = client.domain_cache().and_then(DomainCache::get) {
+        log::trace!("Using cached domains");
+        return Ok(domains);
+    }
+
+    log::debug!("Getting domains");
+    let domains = client.call_async(domains_request(client, None)?).await?;
+    if let Some(cache) = client.domain_cache() {
+        cache.store(&domains);
+    }
+    Ok(domains)
+}
+
+#[cfg(feature = "async")]
+pub(crate) async fn list_async(client: &MailTmClient, page: usize) -> Result<HydraCollection<Domain>, MailTmError> {
+    log::debug!("Getting page {} of domains", page);
+    client.call_async(domains_request(client, Some(page))?).await
+}
 
-    log::trace!("Retrieved domains: {}", response);
-    Ok(serde_json::from_str(&response)?)
+#[cfg(feature = "async")]
+pub(crate) async fn get_async(client: &MailTmClient, id: &str) -> Result<Domain, MailTmError> {
+    log::debug!("Getting domain with id {}", id);
+    client.call_async(get_request(client, id)?).await
+}
+
+#[cfg(feature = "async")]
+pub(crate) async fn find_async(client: &MailTmClient, name: &str) -> Result<Option<Domain>, MailTmError> {
+    log::debug!("Looking for domain {}", name);
+    let mut page = client.domains_async().await?;
+    loop {
+        if let Some(domain) = page.members.iter().find(|domain| matches(domain, name)) {
+            return Ok(Some(domain.clone()));
+        }
+        match page.next_page() {
+            Some(next) => page = client.collection_async(None, next).await?,
+            None => return Ok(None),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock::MockServer;
 
-    fn test_domains() -> Result<(), Error> {
-        let domains = domains()?;
+    fn domain(name: &str, is_active: bool) -> Domain {
+        Domain {
+            domain: name.to_string(),
+            is_active,
+            ..Domain::default()
+        }
+    }
+
+    #[test]
+    fn test_domain_cache() {
+        let cache = DomainCache::new(Duration::from_secs(60));
+        assert!(cache.get().is_none());
+
+        let domains = HydraCollection {
+            members: vec![domain("a.com", true), domain("b.com", false)],
+            total_items: 2,
+            ..HydraCollection::default()
+        };
+        cache.store(&domains);
+        assert_eq!(cache.get(), Some(domains.clone()));
+        assert_eq!(cache.get().unwrap().active(), vec![domain("a.com", true)]);
+        assert_eq!(cache.get().unwrap().any_active(), Some(domain("a.com", true)));
+
+        cache.invalidate();
+        assert!(cache.get().is_none());
+
+        let expired = DomainCache::new(Duration::from_secs(0));
+        expired.store(&domains);
+        assert!(expired.get().is_none());
+    }
+
+    #[test]
+    fn test_requests() -> Result<(), MailTmError> {
+        let client = MailTmClient::builder()?.with_base_url("http://localhost").build()?;
+        assert_eq!(domains_request(&client, None)?.uri(), "http://localhost/domains");
+        assert_eq!(domains_request(&client, Some(2))?.uri(), "http://localhost/domains?page=2");
+        assert_eq!(get_request(&client, "abc")?.uri(), "http://localhost/domains/abc");
+
+        assert!(matches(&domain("mail.tm", true), " @Mail.TM"));
+        assert!(!matches(&domain("mail.tm", true), "mail.gw"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_domains() -> Result<(), MailTmError> {
+        let server = MockServer::start()?;
+        let client = server.client()?;
+        let domains = domains(&client)?;
         assert!(domains.total_items > 0);
 
         let first = domains.members.first().unwrap().clone();
@@ -67,6 +246,12 @@ mod tests {
         let domains = domains.as_list();
 
         assert!(domains.contains(&first.domain));
+
+        assert_eq!(get(&client, &first.id2)?, first);
+        assert_eq!(list(&client, 1)?.members.first(), Some(&first));
+        assert_eq!(find(&client, &first.domain)?, Some(first));
+        assert_eq!(find(&client, "not-a-mail-tm-domain.invalid")?, None);
+        assert!(matches!(get(&client, "missing"), Err(MailTmError::NotFound(_))));
         Ok(())
     }
-}
\ No newline at end of file
+}
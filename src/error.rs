@@ -1,7 +1,259 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::http::header::{InvalidHeaderName, InvalidHeaderValue};
+
+/// Everything that can go wrong talking to Mail-TM
+///
+/// Responses with a non success status are split by what callers usually want to react to, each
+/// carrying the [`ApiError`] the server sent back.
 #[derive(Error, Debug)]
-pub enum HttpError {
-    #[error("Request failed, status: {0} res: {1}")]
-    Status(u16, String),
+pub enum MailTmError {
+    /// The request never got a response, such as dns, tls or connection failures
+    ///
+    /// Holds the error of whichever [`crate::transport::Transport`] sent the request.
+    #[error("Request failed: {0}")]
+    Transport(Box<dyn std::error::Error + Send + Sync>),
+    #[error("Failed to read response: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to build request: {0}")]
+    Request(#[from] crate::http::Error),
+    /// 401, the token is missing or invalid
+    #[error("Unauthorized: {0}")]
+    Unauthorized(ApiError),
+    /// 401 from `/token`, the address or password is wrong
+    #[error("Invalid credentials: {0}")]
+    InvalidCredentials(ApiError),
+    /// 401, the token was valid but has expired
+    #[error("Token expired: {0}")]
+    TokenExpired(ApiError),
+    /// 404
+    #[error("Not found: {0}")]
+    NotFound(ApiError),
+    /// 429
+    #[error("Rate limited: {0}")]
+    RateLimited(ApiError),
+    /// Still 429 after every attempt the [`crate::retry::RetryPolicy`] allowed
+    ///
+    /// `retry_after` is how long the last response asked to wait, if it said.
+    #[error("Rate limited after {attempts} attempts: {error}")]
+    RateLimitExceeded {
+        attempts: u32,
+        retry_after: Option<Duration>,
+        error: ApiError,
+    },
+    /// 400 or 422, the api rejected the input, see [`ApiError::violations`]
+    #[error("Invalid input: {0}")]
+    Violation(ApiError),
+    /// Any other 4xx
+    #[error("Client error: {0}")]
+    Client(ApiError),
+    /// 5xx
+    #[error("Server error: {0}")]
+    Server(ApiError),
+    #[error("Failed to decode response: {source}, body: {body}")]
+    Decode {
+        source: serde_json::Error,
+        body: String,
+    },
+    #[error("No matching message arrived within {0:?}")]
+    Timeout(Duration),
+    #[error("Invalid email address: {0}")]
+    InvalidAddress(String),
+    #[error("Not a valid JWT: {0}")]
+    InvalidToken(String),
+    #[error("Invalid search query: {0}")]
+    InvalidQuery(String),
+    #[error("Not an RFC 3339 date time: {0}")]
+    InvalidTimestamp(String),
+    #[error("No active domains available")]
+    NoActiveDomain,
+}
+
+impl MailTmError {
+    /// Classifies a non success response
+    pub fn from_response(status: u16, body: &str) -> MailTmError {
+        let error = ApiError::parse(status, body);
+        match status {
+            401 if error.message().contains("Expired") => MailTmError::TokenExpired(error),
+            401 => MailTmError::Unauthorized(error),
+            404 => MailTmError::NotFound(error),
+            429 => MailTmError::RateLimited(error),
+            400 | 422 if status == 422 || !error.violations.is_empty() => MailTmError::Violation(error),
+            500..=599 => MailTmError::Server(error),
+            _ => MailTmError::Client(error),
+        }
+    }
+
+    pub(crate) fn decode(source: serde_json::Error, body: &str) -> MailTmError {
+        MailTmError::Decode {
+            source,
+            body: body.to_string(),
+        }
+    }
+
+    /// The error the api responded with, if it responded at all
+    pub fn api_error(&self) -> Option<&ApiError> {
+        match self {
+            MailTmError::Unauthorized(error)
+            | MailTmError::InvalidCredentials(error)
+            | MailTmError::TokenExpired(error)
+            | MailTmError::NotFound(error)
+            | MailTmError::RateLimited(error)
+            | MailTmError::Violation(error)
+            | MailTmError::Client(error)
+            | MailTmError::Server(error)
+            | MailTmError::RateLimitExceeded { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    /// The http status of the response, if there was one
+    pub fn status(&self) -> Option<u16> {
+        self.api_error().map(|error| error.status)
+    }
+
+    /// Whether the same request may well succeed later: rate limits, server errors and failed
+    /// connections
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            MailTmError::Transport(_)
+                | MailTmError::Io(_)
+                | MailTmError::RateLimited(_)
+                | MailTmError::RateLimitExceeded { .. }
+                | MailTmError::Server(_)
+        )
+    }
+
+    /// Whether account creation failed because the address is already in use
+    pub fn is_address_taken(&self) -> bool {
+        match self {
+            MailTmError::Violation(error) => error
+                .violations
+                .iter()
+                .any(|violation| violation.property_path == "address" && violation.message.contains("already used")),
+            _ => false,
+        }
+    }
+}
+
+impl From<InvalidHeaderName> for MailTmError {
+    fn from(err: InvalidHeaderName) -> Self {
+        MailTmError::Request(err.into())
+    }
+}
+
+impl From<InvalidHeaderValue> for MailTmError {
+    fn from(err: InvalidHeaderValue) -> Self {
+        MailTmError::Request(err.into())
+    }
+}
+
+/// An error response from the api
+///
+/// Understands hydra errors, including constraint violation lists, as well as the plain
+/// `{"code", "message"}` bodies the authentication layer returns. Anything else is kept in `body`.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ApiError {
+    pub status: u16,
+    pub title: Option<String>,
+    pub detail: Option<String>,
+    pub violations: Vec<Violation>,
+    pub body: String,
+}
+
+/// A single rejected property from a hydra `ConstraintViolationList`
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Violation {
+    pub property_path: String,
+    pub message: String,
+    pub code: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct ErrorBody {
+    #[serde(rename = "hydra:title")]
+    hydra_title: Option<String>,
+    #[serde(rename = "hydra:description")]
+    hydra_description: Option<String>,
+    title: Option<String>,
+    detail: Option<String>,
+    message: Option<String>,
+    #[serde(default)]
+    violations: Vec<Violation>,
+}
+
+impl ApiError {
+    pub fn parse(status: u16, body: &str) -> ApiError {
+        let parsed: ErrorBody = serde_json::from_str(body).unwrap_or_default();
+        ApiError {
+            status,
+            title: parsed.hydra_title.or(parsed.title),
+            detail: parsed.hydra_description.or(parsed.detail).or(parsed.message),
+            violations: parsed.violations,
+            body: body.to_string(),
+        }
+    }
+
+    /// The most specific description available
+    pub fn message(&self) -> &str {
+        self.detail
+            .as_deref()
+            .or(self.title.as_deref())
+            .unwrap_or(&self.body)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "status: {} res: {}", self.status, self.message())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_violations() {
+        let err = MailTmError::from_response(422, r#"{
+            "@context": "/contexts/ConstraintViolationList",
+            "@type": "ConstraintViolationList",
+            "hydra:title": "An error occurred",
+            "hydra:description": "address: This value is already used.",
+            "violations": [{"propertyPath": "address", "message": "This value is already used."}]
+        }"#);
+
+        assert!(err.is_address_taken());
+        assert_eq!(err.status(), Some(422));
+        let api = err.api_error().unwrap();
+        assert_eq!(api.message(), "address: This value is already used.");
+        assert_eq!(api.violations[0].property_path, "address");
+    }
+
+    #[test]
+    fn test_status_classes() {
+        assert!(matches!(
+            MailTmError::from_response(401, r#"{"code":401,"message":"Expired JWT Token"}"#),
+            MailTmError::TokenExpired(_)
+        ));
+        assert!(matches!(
+            MailTmError::from_response(401, r#"{"code":401,"message":"Invalid JWT Token"}"#),
+            MailTmError::Unauthorized(_)
+        ));
+        assert!(matches!(MailTmError::from_response(404, ""), MailTmError::NotFound(_)));
+        assert!(matches!(MailTmError::from_response(429, ""), MailTmError::RateLimited(_)));
+        assert!(matches!(MailTmError::from_response(400, "bad"), MailTmError::Client(_)));
+        assert!(matches!(MailTmError::from_response(503, "<html>"), MailTmError::Server(error) if error.message() == "<html>"));
+
+        assert!(MailTmError::from_response(429, "").is_transient());
+        assert!(MailTmError::from_response(502, "").is_transient());
+        assert!(!MailTmError::from_response(401, "").is_transient());
+        assert!(!MailTmError::from_response(404, "").is_transient());
+    }
 }
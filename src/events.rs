@@ -0,0 +1,447 @@
+//! Real time updates over Mercure
+//!
+//! Mail-TM publishes every change to an account and its messages as Server-Sent Events on a
+//! [Mercure](https://mercure.rocks/) hub, under the topic `/accounts/{id}`. A [`Subscription`]
+//! keeps that stream open, decodes each event into an [`Update`] and reconnects with the
+//! `Last-Event-ID` header whenever the connection drops.
+
+use std::io::{BufRead, BufReader};
+use std::thread;
+use std::time::Duration;
+
+use crate::accounts::Account;
+use crate::client::MailTmClient;
+use crate::error::MailTmError;
+use crate::http::header::{ACCEPT, CACHE_CONTROL};
+use crate::http::{Method, Request};
+use crate::messages::Message;
+use crate::transport::Body;
+use crate::user::User;
+
+const DEFAULT_RETRY: Duration = Duration::from_secs(3);
+
+/// A decoded update published on the hub
+#[derive(Debug, Clone, PartialEq)]
+pub enum Update {
+    Message(Box<Message>),
+    Account(Box<Account>),
+}
+
+/// An update along with the id the hub gave it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub id: Option<String>,
+    pub update: Update,
+}
+
+/// A raw Server-Sent Event before its data is decoded
+#[derive(Default, Debug, Clone, PartialEq)]
+pub(crate) struct RawEvent {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// Line based Server-Sent Events parser
+///
+/// Fed one line at a time (without its line ending), returns an event each time a blank line
+/// completes one.
+#[derive(Default, Debug)]
+pub(crate) struct EventParser {
+    id: Option<String>,
+    event: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl EventParser {
+    pub fn feed(&mut self, line: &str) -> Option<RawEvent> {
+        if line.is_empty() {
+            let event = self.event.take();
+            return self.data.take().map(|mut data| {
+                if data.ends_with('\n') {
+                    data.pop();
+                }
+                RawEvent {
+                    id: self.id.clone(),
+                    event,
+                    data,
+                }
+            });
+        }
+
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let (field, value) = match line.find(':') {
+            Some(idx) => {
+                let value = &line[idx + 1..];
+                (&line[..idx], value.strip_prefix(' ').unwrap_or(value))
+            }
+            None => (line, ""),
+        };
+
+        match field {
+            "data" => {
+                let data = self.data.get_or_insert_with(String::new);
+                data.push_str(value);
+                data.push('\n');
+            }
+            "event" => self.event = Some(value.to_string()),
+            "id" if !value.contains('\0') => self.id = Some(value.to_string()),
+            "retry" => {
+                if let Ok(ms) = value.parse() {
+                    self.retry = Some(Duration::from_millis(ms));
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// The reconnection delay last requested by the server
+    pub fn retry(&self) -> Option<Duration> {
+        self.retry
+    }
+}
+
+impl RawEvent {
+    /// Decodes the data of the event by its `@type`, events of unknown types are skipped
+    pub fn decode(self) -> Option<Result<Event, MailTmError>> {
+        let value: serde_json::Value = match serde_json::from_str(&self.data) {
+            Ok(value) => value,
+            Err(err) => return Some(Err(MailTmError::decode(err, &self.data))),
+        };
+
+        let update = match value.get("@type").and_then(|t| t.as_str()) {
+            Some("Message") => serde_json::from_value(value).map(|m| Update::Message(Box::new(m))),
+            Some("Account") => serde_json::from_value(value).map(|a| Update::Account(Box::new(a))),
+            other => {
+                log::debug!("Skipping event of unknown type {:?}", other);
+                return None;
+            }
+        };
+
+        let RawEvent { id, data, .. } = self;
+        Some(
+            update
+                .map(|update| Event { id, update })
+                .map_err(|err| MailTmError::decode(err, &data)),
+        )
+    }
+}
+
+fn subscribe_request(client: &MailTmClient, token: &str, topic: &str, last_event_id: Option<&str>) -> Result<Request<String>, MailTmError> {
+    let builder = client
+        .request(Method::GET, &format!("{}?topic={}", client.mercure_url(), topic), Some(token))
+        .header(ACCEPT, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache");
+    let builder = match last_event_id {
+        Some(id) => builder.header("Last-Event-ID", id),
+        None => builder,
+    };
+    Ok(builder.body(String::new())?)
+}
+
+/// A blocking stream of [`Event`]s for one account
+///
+/// Iterating blocks until the next event arrives. Should the connection fail, an error is yielded
+/// and the next call reconnects after the delay requested by the hub. Every connection uses the
+/// user's current token, refreshed like any other request, so a subscription outlives its tokens.
+pub struct Subscription {
+    client: MailTmClient,
+    user: User,
+    topic: String,
+    last_event_id: Option<String>,
+    parser: EventParser,
+    reader: Option<BufReader<Body>>,
+    reconnecting: bool,
+}
+
+impl Subscription {
+    /// The id of the last event received, sent as `Last-Event-ID` when reconnecting
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
+    fn connect(&mut self) -> Result<(), MailTmError> {
+        if self.reconnecting {
+            thread::sleep(self.parser.retry().unwrap_or(DEFAULT_RETRY));
+        }
+        self.reconnecting = true;
+
+        log::debug!("Subscribing to {} from event {:?}", self.topic, self.last_event_id);
+        let response = self.client.authorized(&self.user, |token| {
+            let request = subscribe_request(&self.client, token, &self.topic, self.last_event_id.as_deref())?;
+            self.client.open(request)
+        })?;
+        self.parser = EventParser {
+            retry: self.parser.retry(),
+            ..EventParser::default()
+        };
+        self.reader = Some(BufReader::new(response.into_body()));
+        Ok(())
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Event, MailTmError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let reader = match self.reader.as_mut() {
+                Some(reader) => reader,
+                None => {
+                    if let Err(err) = self.connect() {
+                        return Some(Err(err));
+                    }
+                    continue;
+                }
+            };
+
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) => {
+                    log::debug!("Event stream for {} closed, reconnecting", self.topic);
+                    self.reader = None;
+                }
+                Ok(_) => {
+                    let line = line.trim_end_matches(&['\r', '\n'][..]);
+                    if let Some(event) = self.parser.feed(line) {
+                        // Events without an id keep the last one, as in the SSE spec
+                        if event.id.is_some() {
+                            self.last_event_id = event.id.clone();
+                        }
+                        if let Some(event) = event.decode() {
+                            return Some(event);
+                        }
+                    }
+                }
+                Err(err) => {
+                    self.reader = None;
+                    return Some(Err(err.into()));
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn subscribe(client: &MailTmClient, user: &User, account_id: &str) -> Subscription {
+    Subscription {
+        client: client.clone(),
+        user: user.clone(),
+        topic: format!("/accounts/{}", account_id),
+        last_event_id: None,
+        parser: EventParser::default(),
+        reader: None,
+        reconnecting: false,
+    }
+}
+
+#[cfg(feature = "async")]
+pub(crate) fn subscribe_async(client: &MailTmClient, user: &User, account_id: &str) -> impl futures_lite::Stream<Item = Result<Event, MailTmError>> + Send + 'static {
+    use futures_lite::io::BufReader;
+    use futures_lite::{stream, AsyncBufReadExt};
+
+    use crate::transport::AsyncBody;
+
+    struct State {
+        subscription: Subscription,
+        reader: Option<BufReader<AsyncBody>>,
+    }
+
+    impl State {
+        async fn connect(&mut self) -> Result<(), MailTmError> {
+            let subscription = &mut self.subscription;
+            if subscription.reconnecting {
+                futures_timer::Delay::new(subscription.parser.retry().unwrap_or(DEFAULT_RETRY)).await;
+            }
+            subscription.reconnecting = true;
+
+            log::debug!("Subscribing to {} from event {:?}", subscription.topic, subscription.last_event_id);
+            // Owned copies, the subscription itself is not Sync and cannot be shared with the future
+            let client = subscription.client.clone();
+            let (topic, last_event_id) = (subscription.topic.clone(), subscription.last_event_id.clone());
+            let response = client
+                .authorized_async(&subscription.user, |token| {
+                    let (client, topic, last_event_id) = (&client, &topic, &last_event_id);
+                    async move {
+                        let request = subscribe_request(client, &token, topic, last_event_id.as_deref())?;
+                        client.open_async(request).await
+                    }
+                })
+                .await?;
+            subscription.parser = EventParser {
+                retry: subscription.parser.retry(),
+                ..EventParser::default()
+            };
+            self.reader = Some(BufReader::new(response.into_body()));
+            Ok(())
+        }
+
+        async fn next_event(&mut self) -> Result<Event, MailTmError> {
+            loop {
+                let reader = match self.reader.as_mut() {
+                    Some(reader) => reader,
+                    None => {
+                        self.connect().await?;
+                        continue;
+                    }
+                };
+
+                let mut line = String::new();
+                match reader.read_line(&mut line).await {
+                    Ok(0) => {
+                        log::debug!("Event stream for {} closed, reconnecting", self.subscription.topic);
+                        self.reader = None;
+                    }
+                    Ok(_) => {
+                        let line = line.trim_end_matches(&['\r', '\n'][..]);
+                        if let Some(event) = self.subscription.parser.feed(line) {
+                            if event.id.is_some() {
+                                self.subscription.last_event_id = event.id.clone();
+                            }
+                            if let Some(event) = event.decode() {
+                                return event;
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        self.reader = None;
+                        return Err(err.into());
+                    }
+                }
+            }
+        }
+    }
+
+    let state = State {
+        subscription: subscribe(client, user, account_id),
+        reader: None,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        let event = state.next_event().await;
+        Some((event, state))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Vec<RawEvent> {
+        let mut parser = EventParser::default();
+        input.lines().filter_map(|line| parser.feed(line)).collect()
+    }
+
+    #[test]
+    fn test_parse_events() {
+        let events = parse(": keep alive\n\nid: 1\nevent: update\ndata: {\"a\":\ndata: 1}\n\nretry: 10\ndata: x\n\n");
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].id.as_deref(), Some("1"));
+        assert_eq!(events[0].event.as_deref(), Some("update"));
+        assert_eq!(events[0].data, "{\"a\":\n1}");
+        assert_eq!(events[1].id.as_deref(), Some("1"));
+        assert_eq!(events[1].event, None);
+        assert_eq!(events[1].data, "x");
+    }
+
+    #[test]
+    fn test_retry() {
+        let mut parser = EventParser::default();
+        parser.feed("retry: 1500");
+        parser.feed("retry: soon");
+        assert_eq!(parser.retry(), Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn test_subscription_refreshes_token() -> Result<(), MailTmError> {
+        use crate::http::header::AUTHORIZATION;
+        use crate::http::Response;
+        use crate::transport::MemoryTransport;
+
+        let transport = MemoryTransport::with_handler(|request| {
+            let authorized = request.headers.get(AUTHORIZATION).is_some_and(|value| value == "Bearer fresh");
+            let (status, body) = if request.uri.ends_with("/token") {
+                (200, r#"{"token":"fresh","id":"1"}"#.to_string())
+            } else if authorized {
+                (200, format!("id: 1\ndata: {}\n\n", r#"{"@type":"Account","address":"a@b.c","quota":1,"used":0,"isDisabled":false}"#))
+            } else {
+                (401, r#"{"code":401,"message":"Expired JWT Token"}"#.to_string())
+            };
+            Response::builder().status(status).body(body.into_bytes()).unwrap()
+        });
+        let client = MailTmClient::builder()?
+            .with_base_url("http://localhost")
+            .with_mercure_url("http://localhost/.well-known/mercure")
+            .with_transport(transport.clone())
+            .build()?;
+        let user = crate::update_token(&User::new("a", "hunter22", "b.c"), "stale");
+
+        let event = client.subscribe(&user, "1").next().unwrap()?;
+        assert!(matches!(event.update, Update::Account(_)));
+        let uris: Vec<_> = transport.requests().into_iter().map(|request| request.uri).collect();
+        assert_eq!(uris.len(), 3);
+        assert!(uris[1].ends_with("/token"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_last_event_id_survives_events_without_id() -> Result<(), MailTmError> {
+        use crate::http::Response;
+        use crate::transport::MemoryTransport;
+
+        let account = r#"{"@type":"Account","address":"a@b.c","quota":1,"used":0,"isDisabled":false}"#;
+        let transport = MemoryTransport::with_handler(move |request| {
+            let body = match request.headers.get("Last-Event-ID") {
+                None => format!("retry: 1\nid: 1\ndata: {}\n\n", account),
+                Some(_) => format!("data: {}\n\n", account),
+            };
+            Response::new(body.into_bytes())
+        });
+        let client = MailTmClient::builder()?
+            .with_base_url("http://localhost")
+            .with_mercure_url("http://localhost/.well-known/mercure")
+            .with_transport(transport.clone())
+            .with_token_refresh(false)
+            .build()?;
+        let user = crate::update_token(&User::new("a", "hunter22", "b.c"), "token");
+
+        let mut subscription = client.subscribe(&user, "1");
+        assert_eq!(subscription.next().unwrap()?.id.as_deref(), Some("1"));
+        assert_eq!(subscription.next().unwrap()?.id, None);
+        assert_eq!(subscription.last_event_id(), Some("1"));
+        subscription.next().unwrap()?;
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].headers.get("Last-Event-ID").unwrap(), "1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode() {
+        let account = RawEvent {
+            id: Some("urn:uuid:1".to_string()),
+            event: None,
+            data: r#"{"@type":"Account","address":"a@b.c","quota":1,"used":0,"isDisabled":false,"createdAt":null,"updatedAt":null}"#.to_string(),
+        };
+        let event = account.decode().unwrap().unwrap();
+        assert_eq!(event.id.as_deref(), Some("urn:uuid:1"));
+        assert!(matches!(event.update, Update::Account(account) if account.address == "a@b.c"));
+
+        let unknown = RawEvent {
+            data: r#"{"@type":"Domain"}"#.to_string(),
+            ..RawEvent::default()
+        };
+        assert!(unknown.decode().is_none());
+
+        let broken = RawEvent {
+            data: r#"{"@type":"Message"}"#.to_string(),
+            ..RawEvent::default()
+        };
+        assert!(broken.decode().unwrap().is_err());
+    }
+}
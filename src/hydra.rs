@@ -1,6 +1,16 @@
+use std::collections::{HashSet, VecDeque};
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use rand::seq::SliceRandom;
 use rand::Rng;
 
+use crate::client::MailTmClient;
+use crate::error::MailTmError;
+use crate::http::{Method, Request};
+use crate::user::User;
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct HydraCollection<T>  {
@@ -15,24 +25,244 @@ pub struct HydraCollection<T>  {
 }
 
 impl <T: Clone> HydraCollection<T> {
-    pub fn any(&self) -> T {
-        self.members[rand::thread_rng().gen_range(0..self.members.len())].clone()
+    /// A random member, `None` if the collection is empty
+    pub fn any(&self) -> Option<T> {
+        self.choose_with(&mut rand::thread_rng())
+    }
+
+    /// A random member picked with `rng`, for instance a seeded one in tests
+    pub fn choose_with<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<T> {
+        self.members.choose(rng).cloned()
+    }
+
+    /// A random member out of those matching `predicate`
+    pub fn any_where<P: FnMut(&T) -> bool>(&self, predicate: P) -> Option<T> {
+        self.choose_where_with(&mut rand::thread_rng(), predicate)
+    }
+
+    pub fn choose_where_with<R: Rng + ?Sized, P: FnMut(&T) -> bool>(&self, rng: &mut R, mut predicate: P) -> Option<T> {
+        let matching: Vec<&T> = self.members.iter().filter(|member| predicate(member)).collect();
+        matching.choose(rng).map(|member| (*member).clone())
+    }
+
+    /// A random member, each picked in proportion to its `weight`
+    ///
+    /// `None` if the collection is empty or no member has a positive weight.
+    pub fn choose_weighted_with<R: Rng + ?Sized, W: Fn(&T) -> f64>(&self, rng: &mut R, weight: W) -> Option<T> {
+        self.members
+            .choose_weighted(rng, |member| weight(member).max(0.0))
+            .ok()
+            .cloned()
+    }
+}
+
+impl <T> HydraCollection<T> {
+    /// The link to the next page, if there is one
+    pub fn next_page(&self) -> Option<&str> {
+        self.view.as_ref()?.next.as_deref()
+    }
+
+    pub fn has_next(&self) -> bool {
+        self.next_page().is_some()
+    }
+
+    /// The number of this page, collections without a view fit on a single page
+    pub fn current_page(&self) -> usize {
+        self.view
+            .as_ref()
+            .and_then(View::current_page)
+            .unwrap_or(1)
+    }
+
+    /// The number of pages in the collection, read from the `hydra:last` link
+    pub fn page_count(&self) -> usize {
+        self.view
+            .as_ref()
+            .and_then(View::last_page)
+            .unwrap_or_else(|| self.current_page())
+    }
+}
+
+/// Lazily walks every item of a hydra collection
+///
+/// Fetches one page at a time, following `hydra:next` until the collection is exhausted or the
+/// optional cap set with [`HydraIter::with_max_items`] is reached. Iteration stops after yielding
+/// an error.
+pub struct HydraIter<T> {
+    client: MailTmClient,
+    user: Option<User>,
+    next: Option<String>,
+    items: VecDeque<T>,
+    max_items: Option<usize>,
+    yielded: usize,
+}
+
+fn page_request(client: &MailTmClient, token: Option<&str>, path: &str) -> Result<Request<String>, MailTmError> {
+    Ok(client
+        .request(Method::GET, path, token)
+        .body(String::new())?)
+}
+
+#[cfg(feature = "async")]
+pub(crate) async fn fetch_async<T: DeserializeOwned>(client: &MailTmClient, user: Option<&User>, path: &str) -> Result<HydraCollection<T>, MailTmError> {
+    log::debug!("Fetching page {}", path);
+    match user {
+        Some(user) => client
+            .authorized_async(user, |token| async move { client.call_async(page_request(client, Some(&token), path)?).await })
+            .await,
+        None => client.call_async(page_request(client, None, path)?).await,
     }
 }
 
+impl <T: DeserializeOwned> HydraIter<T> {
+    /// Starts at `path`, authenticating as `user` when given one
+    pub(crate) fn new(client: &MailTmClient, user: Option<&User>, path: &str) -> HydraIter<T> {
+        HydraIter {
+            client: client.clone(),
+            user: user.cloned(),
+            next: Some(path.to_string()),
+            items: VecDeque::new(),
+            max_items: None,
+            yielded: 0,
+        }
+    }
+
+    /// Stops after yielding `max_items` items
+    pub fn with_max_items(self, max_items: usize) -> HydraIter<T> {
+        HydraIter {
+            max_items: Some(max_items),
+            ..self
+        }
+    }
+
+    fn capped(&self) -> bool {
+        self.max_items.map(|max| self.yielded >= max).unwrap_or(false)
+    }
+
+    fn load(&mut self, path: &str, page: HydraCollection<T>) {
+        self.next = page
+            .next_page()
+            .filter(|next| *next != path)
+            .map(str::to_string);
+        if page.members.is_empty() {
+            self.next = None;
+        }
+        self.items.extend(page.members);
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let item = self.items.pop_front()?;
+        self.yielded += 1;
+        Some(item)
+    }
+
+    fn fetch(&self, path: &str) -> Result<HydraCollection<T>, MailTmError> {
+        log::debug!("Fetching page {}", path);
+        let client = &self.client;
+        match &self.user {
+            Some(user) => client.authorized(user, |token| client.call(page_request(client, Some(token), path)?)),
+            None => client.call(page_request(client, None, path)?),
+        }
+    }
+
+    #[cfg(feature = "async")]
+    async fn next_async(&mut self) -> Option<Result<T, MailTmError>> {
+        loop {
+            if self.capped() {
+                return None;
+            }
+            if let Some(item) = self.pop() {
+                return Some(Ok(item));
+            }
+            let path = self.next.take()?;
+            match fetch_async(&self.client, self.user.as_ref(), &path).await {
+                Ok(page) => self.load(&path, page),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+
+    /// Walks the collection as a [`futures_lite::Stream`] instead
+    #[cfg(feature = "async")]
+    pub fn into_stream(self) -> impl futures_lite::Stream<Item = Result<T, MailTmError>> + Send + 'static
+        where T: Send + 'static {
+        futures_lite::stream::unfold(self, |mut iter| async move {
+            let item = iter.next_async().await?;
+            Some((item, iter))
+        })
+    }
+}
+
+impl <T: DeserializeOwned> Iterator for HydraIter<T> {
+    type Item = Result<T, MailTmError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.capped() {
+                return None;
+            }
+            if let Some(item) = self.pop() {
+                return Some(Ok(item));
+            }
+            let path = self.next.take()?;
+            match self.fetch(&path) {
+                Ok(page) => self.load(&path, page),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// Paging links of a collection
+///
+/// The api leaves out whichever links do not apply, such as `hydra:next` on the last page.
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct View {
-    #[serde(rename = "@id")]
+    #[serde(rename = "@id", default)]
     pub id: String,
-    #[serde(rename = "@type")]
+    #[serde(rename = "@type", default)]
     pub type_field: String,
-    #[serde(rename = "hydra:first")]
-    pub first: String,
-    #[serde(rename = "hydra:last")]
-    pub last: String,
-    #[serde(rename = "hydra:next")]
-    pub next: String,
+    #[serde(rename = "hydra:first", skip_serializing_if = "Option::is_none")]
+    pub first: Option<String>,
+    #[serde(rename = "hydra:last", skip_serializing_if = "Option::is_none")]
+    pub last: Option<String>,
+    #[serde(rename = "hydra:next", skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+    #[serde(rename = "hydra:previous", skip_serializing_if = "Option::is_none")]
+    pub previous: Option<String>,
+}
+
+impl View {
+    pub fn current_page(&self) -> Option<usize> {
+        page_number(&self.id)
+    }
+
+    pub fn first_page(&self) -> Option<usize> {
+        page_number(self.first.as_deref()?)
+    }
+
+    pub fn last_page(&self) -> Option<usize> {
+        page_number(self.last.as_deref()?)
+    }
+
+    pub fn next_page(&self) -> Option<usize> {
+        page_number(self.next.as_deref()?)
+    }
+
+    pub fn previous_page(&self) -> Option<usize> {
+        page_number(self.previous.as_deref()?)
+    }
+}
+
+/// Reads the `page` query parameter of a link such as `/messages?page=2`
+fn page_number(link: &str) -> Option<usize> {
+    let (_, query) = link.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == "page")
+        .and_then(|(_, value)| value.parse().ok())
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -58,3 +288,302 @@ pub struct Mapping {
     pub required: bool,
 }
 
+impl Search {
+    /// Starts a query against this search template
+    pub fn query(&self) -> SearchQuery<'_> {
+        SearchQuery {
+            search: self,
+            values: Vec::new(),
+        }
+    }
+
+    /// Every variable the template or its mappings mention
+    pub fn variables(&self) -> Vec<&str> {
+        let mut seen = HashSet::new();
+        expressions(&self.template)
+            .flat_map(|(_, _, names)| names.split(','))
+            .chain(self.mapping.iter().map(|mapping| mapping.variable.as_str()))
+            .filter(|variable| seen.insert(*variable))
+            .collect()
+    }
+}
+
+/// Fills in the variables of a [`Search`] template
+///
+/// Only variables the collection advertises are accepted, and every mapping marked as required
+/// must be given before [`SearchQuery::build`] expands the template into a request path.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::hydra::Search;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let search = Search {
+///         template: "/messages{?page,seen}".to_string(),
+///         ..Search::default()
+///     };
+///     let path = search.query().page(2)?.with("seen", false)?.build()?;
+///     assert_eq!(path, "/messages?page=2&seen=false");
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SearchQuery<'a> {
+    search: &'a Search,
+    values: Vec<(String, String)>,
+}
+
+impl <'a> SearchQuery<'a> {
+    /// Sets a variable, replacing any earlier value
+    pub fn with<V: ToString>(mut self, variable: &str, value: V) -> Result<SearchQuery<'a>, MailTmError> {
+        if !self.search.variables().contains(&variable) {
+            return Err(MailTmError::InvalidQuery(format!(
+                "{} is not supported by {}", variable, self.search.template
+            )));
+        }
+        self.values.retain(|(name, _)| name != variable);
+        self.values.push((variable.to_string(), value.to_string()));
+        Ok(self)
+    }
+
+    pub fn page(self, page: usize) -> Result<SearchQuery<'a>, MailTmError> {
+        self.with("page", page)
+    }
+
+    fn value(&self, variable: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(name, _)| name == variable)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Expands the template into a path, failing if a required variable is missing
+    ///
+    /// Simple `{var}` expressions and the query operators `{?var}` and `{&var}` are supported, a
+    /// template using any other operator, such as `{/var}` or `{+var}`, fails to build. Unset query
+    /// variables are left out, while every variable of a simple expression must be set as it is
+    /// part of the path.
+    pub fn build(&self) -> Result<String, MailTmError> {
+        let template = &self.search.template;
+        if let Some((expression, _, _)) = expressions(template).find(|(_, operator, _)| !matches!(operator, None | Some('?') | Some('&'))) {
+            return Err(MailTmError::InvalidQuery(format!("{} uses an unsupported operator", expression)));
+        }
+        if let Some(missing) = self.search.mapping.iter().find(|m| m.required && self.value(&m.variable).is_none()) {
+            return Err(MailTmError::InvalidQuery(format!("{} is required", missing.variable)));
+        }
+
+        let mut path = String::new();
+        let mut rest = template.as_str();
+        for (expression, operator, names) in expressions(template) {
+            if operator.is_none() {
+                if let Some(missing) = names.split(',').find(|name| self.value(name).is_none()) {
+                    return Err(MailTmError::InvalidQuery(format!("{} is required by {}", missing, expression)));
+                }
+            }
+            let start = rest.find(expression).unwrap_or(0);
+            path.push_str(&rest[..start]);
+            rest = &rest[start + expression.len()..];
+
+            let pairs: Vec<String> = names
+                .split(',')
+                .filter_map(|name| self.value(name).map(|value| (name, value)))
+                .map(|(name, value)| match operator {
+                    Some(_) => format!("{}={}", name, encode(value)),
+                    None => encode(value),
+                })
+                .collect();
+            if pairs.is_empty() {
+                continue;
+            }
+            match operator {
+                Some('?') if !path.contains('?') => path.push('?'),
+                Some(_) => path.push('&'),
+                None => {}
+            }
+            path.push_str(&pairs.join(match operator {
+                Some(_) => "&",
+                None => ",",
+            }));
+        }
+        path.push_str(rest);
+        Ok(path)
+    }
+}
+
+/// The `{...}` expressions of a uri template along with their operator, if any, and comma
+/// separated variable names
+fn expressions(template: &str) -> impl Iterator<Item = (&str, Option<char>, &str)> {
+    template.split('{').skip(1).filter_map(move |part| {
+        let end = part.find('}')?;
+        let names = &part[..end];
+        let start = template.find(&format!("{{{}}}", names))?;
+        let expression = &template[start..start + end + 2];
+        let operator = names
+            .chars()
+            .next()
+            .filter(|c| !c.is_ascii_alphanumeric() && *c != '_' && *c != '%');
+        Some((expression, operator, &names[operator.map_or(0, char::len_utf8)..]))
+    })
+}
+
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, QUERY_VALUE).to_string()
+}
+
+const QUERY_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn page(members: &[i64], next: &str) -> HydraCollection<i64> {
+        HydraCollection {
+            members: members.to_vec(),
+            total_items: 5,
+            view: Some(View {
+                next: Some(next.to_string()),
+                ..View::default()
+            }),
+            search: None,
+        }
+    }
+
+    #[test]
+    fn test_iter_paging() -> Result<(), MailTmError> {
+        let client = MailTmClient::builder()?.with_base_url("http://localhost").build()?;
+        let mut iter: HydraIter<i64> = HydraIter::new(&client, None, "/domains?page=1").with_max_items(4);
+
+        assert_eq!(iter.next.take().as_deref(), Some("/domains?page=1"));
+        iter.load("/domains?page=1", page(&[1, 2, 3], "/domains?page=2"));
+        assert_eq!(iter.next.as_deref(), Some("/domains?page=2"));
+        assert_eq!(iter.by_ref().take(3).collect::<Result<Vec<_>, _>>()?, vec![1, 2, 3]);
+
+        iter.next = None;
+        iter.load("/domains?page=2", page(&[4, 5], "/domains?page=2"));
+        assert_eq!(iter.next, None);
+        assert_eq!(iter.collect::<Result<Vec<_>, _>>()?, vec![4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_choose() {
+        let empty: HydraCollection<i64> = HydraCollection::default();
+        assert_eq!(empty.any(), None);
+        assert_eq!(empty.any_where(|_| true), None);
+
+        let collection = HydraCollection {
+            members: (1..=10).collect(),
+            ..HydraCollection::default()
+        };
+        let picks: Vec<_> = (0..5).map(|_| collection.choose_with(&mut StdRng::seed_from_u64(7))).collect();
+        assert!(picks.windows(2).all(|pair| pair[0] == pair[1]));
+        assert!(collection.any().is_some());
+
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..20 {
+            assert_eq!(collection.choose_where_with(&mut rng, |n| n % 2 == 0).map(|n| n % 2), Some(0));
+            assert_eq!(collection.choose_weighted_with(&mut rng, |n| if *n == 3 { 1.0 } else { 0.0 }), Some(3));
+        }
+        assert_eq!(collection.any_where(|n| *n > 10), None);
+        assert_eq!(collection.choose_weighted_with(&mut rng, |_| -1.0), None);
+    }
+
+    fn search() -> Search {
+        Search {
+            type_field: "hydra:IriTemplate".to_string(),
+            template: "/messages{?page,createdAt[before],seen}".to_string(),
+            variable_representation: "BasicRepresentation".to_string(),
+            mapping: vec![
+                Mapping {
+                    variable: "createdAt[before]".to_string(),
+                    property: "createdAt".to_string(),
+                    ..Mapping::default()
+                },
+                Mapping {
+                    variable: "seen".to_string(),
+                    property: "seen".to_string(),
+                    required: true,
+                    ..Mapping::default()
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_search_query() -> Result<(), MailTmError> {
+        let search = search();
+
+        let path = search
+            .query()
+            .with("seen", true)?
+            .with("createdAt[before]", "2021-01-01T00:00:00+00:00")?
+            .page(3)?
+            .build()?;
+        assert_eq!(path, "/messages?page=3&createdAt[before]=2021-01-01T00%3A00%3A00%2B00%3A00&seen=true");
+
+        assert!(matches!(search.query().with("subject", "hi"), Err(MailTmError::InvalidQuery(_))));
+        assert!(matches!(search.query().page(1)?.build(), Err(MailTmError::InvalidQuery(_))));
+
+        let simple = Search {
+            template: "/accounts/{id}/messages{?page}".to_string(),
+            ..Search::default()
+        };
+        assert_eq!(simple.query().with("id", "a b")?.build()?, "/accounts/a%20b/messages");
+        assert!(matches!(simple.query().page(2)?.build(), Err(MailTmError::InvalidQuery(_))));
+
+        assert_eq!(search.variables(), vec!["page", "createdAt[before]", "seen"]);
+        for template in &["/accounts{/id}", "/messages{+path}", "/messages{#section}", "/messages{;seen}", "/messages{.format}"] {
+            let unsupported = Search {
+                template: template.to_string(),
+                ..Search::default()
+            };
+            assert!(matches!(unsupported.query().build(), Err(MailTmError::InvalidQuery(_))), "{}", template);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_view_links() -> Result<(), serde_json::Error> {
+        let last: HydraCollection<i64> = serde_json::from_str(r#"{
+            "hydra:member": [1],
+            "hydra:totalItems": 61,
+            "hydra:view": {
+                "@id": "/messages?page=3",
+                "@type": "hydra:PartialCollectionView",
+                "hydra:first": "/messages?page=1",
+                "hydra:last": "/messages?page=3",
+                "hydra:previous": "/messages?page=2"
+            }
+        }"#)?;
+        assert!(!last.has_next());
+        assert_eq!(last.current_page(), 3);
+        assert_eq!(last.page_count(), 3);
+        let view = last.view.unwrap();
+        assert_eq!(view.previous_page(), Some(2));
+        assert_eq!(view.first_page(), Some(1));
+        assert_eq!(view.next_page(), None);
+
+        let single: HydraCollection<i64> = serde_json::from_str(r#"{
+            "hydra:member": [1, 2],
+            "hydra:totalItems": 2,
+            "hydra:view": {"@id": "/domains", "@type": "hydra:PartialCollectionView"}
+        }"#)?;
+        assert!(!single.has_next());
+        assert_eq!(single.current_page(), 1);
+        assert_eq!(single.page_count(), 1);
+
+        let bare: HydraCollection<i64> = serde_json::from_str(r#"{"hydra:member": [], "hydra:totalItems": 0}"#)?;
+        assert_eq!(bare.page_count(), 1);
+
+        assert_eq!(page_number("/messages?foo=bar&page=12"), Some(12));
+        assert_eq!(page_number("/messages"), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+//! Throwaway inboxes that clean up after themselves
+
+use std::time::Duration;
+
+use crate::accounts::Account;
+use crate::client::MailTmClient;
+use crate::error::MailTmError;
+use crate::hydra::{HydraCollection, HydraIter};
+use crate::messages::Message;
+use crate::user::User;
+use crate::{default_client, update_token};
+
+/// An account that is deleted again when dropped
+///
+/// Creates a random user on one of the active domains and logs it in. Dropping the inbox deletes
+/// the account, including during a panic, so tests never leave accounts behind on the server. Call
+/// [`TempInbox::keep`] to hold on to the account instead.
+///
+/// # Example
+/// ```no_run
+/// use std::time::Duration;
+/// use mail_tm_rs::inbox::TempInbox;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let inbox = TempInbox::new()?;
+///     println!("send something to {}", inbox.address());
+///     let message = inbox.wait_for_message(|_| true, Duration::from_secs(60))?;
+///     Ok(())
+/// }
+/// ```
+pub struct TempInbox {
+    client: MailTmClient,
+    user: User,
+    account: Account,
+    keep: bool,
+}
+
+impl TempInbox {
+    /// Creates an inbox through the [`default_client`]
+    pub fn new() -> Result<TempInbox, MailTmError> {
+        TempInbox::with_client(default_client()?)
+    }
+
+    pub fn with_client(client: &MailTmClient) -> Result<TempInbox, MailTmError> {
+        let domain = client.domains()?.any_active().ok_or(MailTmError::NoActiveDomain)?;
+        let user = User::default().with_domain(&domain.domain);
+        let account = client.create_account(&user)?;
+        let mut inbox = TempInbox {
+            client: client.clone(),
+            user,
+            account,
+            keep: false,
+        };
+        // Dropping here on failure already deletes the account
+        inbox.user = update_token(&inbox.user, &client.token(&inbox.user)?.token);
+        log::debug!("Created temporary inbox {}", inbox.address());
+        Ok(inbox)
+    }
+
+    /// The logged in user owning the inbox
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+
+    pub fn account(&self) -> &Account {
+        &self.account
+    }
+
+    pub fn address(&self) -> &str {
+        &self.account.address
+    }
+
+    pub fn client(&self) -> &MailTmClient {
+        &self.client
+    }
+
+    /// See [`crate::list_messages`]
+    pub fn messages(&self, page: Option<usize>) -> Result<HydraCollection<Message>, MailTmError> {
+        self.client.list_messages(&self.user, page)
+    }
+
+    /// See [`crate::iter_messages`]
+    pub fn iter_messages(&self) -> HydraIter<Message> {
+        self.client.iter_messages(&self.user)
+    }
+
+    /// See [`crate::get_message`]
+    pub fn get_message(&self, id: &str) -> Result<Message, MailTmError> {
+        self.client.get_message(&self.user, id)
+    }
+
+    /// See [`crate::delete_message`]
+    pub fn delete_message(&self, id: &str) -> Result<(), MailTmError> {
+        self.client.delete_message(&self.user, id)
+    }
+
+    /// See [`crate::wait_for_message`]
+    pub fn wait_for_message<P: FnMut(&Message) -> bool>(&self, predicate: P, timeout: Duration) -> Result<Message, MailTmError> {
+        self.client.wait_for_message(&self.user, predicate, timeout)
+    }
+
+    /// Disarms the guard, returning the user and account so they outlive the inbox
+    pub fn keep(mut self) -> (User, Account) {
+        self.keep = true;
+        (self.user.clone(), self.account.clone())
+    }
+}
+
+impl Drop for TempInbox {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+        let id = match &self.account.id {
+            Some(id) => id,
+            None => return,
+        };
+        log::debug!("Deleting temporary inbox {}", self.account.address);
+        if let Err(err) = self.client.delete_account(&self.user, id) {
+            log::warn!("Failed to delete temporary inbox {}: {}", self.account.address, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+
+    #[test]
+    fn test_temp_inbox() -> Result<(), MailTmError> {
+        let server = MockServer::start()?;
+        let client = server.client()?;
+
+        let inbox = TempInbox::with_client(&client)?;
+        let user = inbox.user().clone();
+        let id = inbox.account().id.clone().unwrap();
+        assert_eq!(inbox.messages(None)?.total_items, 0);
+        drop(inbox);
+        assert!(client.get_account(&user, &id).is_err());
+
+        let (user, account) = TempInbox::with_client(&client)?.keep();
+        let id = account.id.unwrap();
+        assert_eq!(client.get_account(&user, &id)?.address, account.address);
+        client.delete_account(&user, &id)
+    }
+}
@@ -1,46 +1,79 @@
 //! Mail-TM API implementation using common HTTP crates
 //!
 //! Provides an implementation of the Mail-TM 2.0.0 API
-//! Largely it is around 80% complete and is missing possibly future deprecations such as sources.
 //! At present the dependencies are very strict and requires future testing to open it up.
 //!
 //! Expect some breaking changes until v1.0.0 but will try to document them as best I can.
 //!
 //! [`Mail-TM`]: https://mail.tm/
 
-use anyhow::{Context, Error};
-use once_cell::sync::Lazy;
-use rand::prelude::*;
+use std::io::Write;
+use std::time::Duration;
+
+use once_cell::sync::{Lazy, OnceCell};
 
 use token::Token;
 use accounts::Account;
 use user::User;
-use crate::hydra::HydraCollection;
+use events::Subscription;
+use crate::error::MailTmError;
+use crate::hydra::{HydraCollection, HydraIter};
 use crate::domains::Domain;
-use crate::messages::Message;
+use crate::messages::{Attachment, Message};
+
+pub use client::{BaseUrl, MailTmClient, MailTmClientBuilder};
 
 pub mod token;
 pub mod accounts;
+pub mod auth;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod client;
 pub mod domains;
 pub mod messages;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod rate_limit;
+pub mod retry;
+pub mod sources;
 pub mod error;
+pub mod events;
 pub mod http;
 pub mod hydra;
+pub mod inbox;
+pub mod timestamp;
+pub mod transport;
 pub mod user;
 
-pub static MAIL_API_URL: Lazy<String> = {
-    const POTENTIALS: [&str; 2] = [
-        "https://api.mail.tm",
-        "https://api.mail.gw",
-    ];
-    Lazy::new(|| {
-        let mut rng = thread_rng();
-        POTENTIALS.choose(&mut rng).unwrap().to_string()
-    })
-};
+/// The hosts serving the Mail-TM api, the first is used unless told otherwise
+pub const API_URLS: [&str; 2] = [
+    "https://api.mail.tm",
+    "https://api.mail.gw",
+];
+
+/// The Mercure hub publishing real time account and message updates
+pub const MERCURE_URL: &str = "https://mercure.mail.tm/.well-known/mercure";
+
+/// Environment variable that overrides the default base url, e.g. to point at a local server
+pub const API_URL_ENV: &str = "MAIL_TM_API_URL";
+
+/// The base url used when a client is not given one explicitly
+///
+/// Resolved once from [`API_URL_ENV`], falling back to the first of [`API_URLS`]. Clients resolve
+/// their [`BaseUrl`] themselves and no longer read this.
+#[deprecated(note = "use BaseUrl")]
+pub static MAIL_API_URL: Lazy<String> = Lazy::new(|| BaseUrl::Default.resolve());
 
-pub(crate) const USER_AGENT: &str = "Isahc; mail-tm-rs";
+pub(crate) const USER_AGENT: &str = concat!("mail-tm-rs/", env!("CARGO_PKG_VERSION"));
 
+static DEFAULT_CLIENT: OnceCell<MailTmClient> = OnceCell::new();
+
+/// The client shared by the free functions in this module
+///
+/// Built lazily on first use so every free function call reuses the same connection pool.
+pub fn default_client() -> Result<&'static MailTmClient, MailTmError> {
+    DEFAULT_CLIENT.get_or_try_init(MailTmClient::new)
+}
 
 /// Creates an account based on a user
 ///
@@ -52,14 +85,14 @@ pub(crate) const USER_AGENT: &str = "Isahc; mail-tm-rs";
 /// use mail_tm_rs::{create_account, update_token, token, domains};
 ///
 /// fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     //let user = User::default().with_domain(&domains().await?.any().domain);
+///     //let user = User::default().with_domain(&domains().await?.any_active().ok_or("no active domains")?.domain);
 ///     //let create = create_account(&user).await?;
 ///     //let user = update_token(&user, &token(&user).await?.token);
 ///     Ok(())
 /// }
 /// ```
-pub fn create_account(user: &User) -> Result<Account, Error> {
-    accounts::create(user)
+pub fn create_account(user: &User) -> Result<Account, MailTmError> {
+    default_client()?.create_account(user)
 }
 
 /// Retrieve an account
@@ -72,15 +105,15 @@ pub fn create_account(user: &User) -> Result<Account, Error> {
 /// use mail_tm_rs::{create_account, get_account, update_token, token, domains};
 ///
 /// fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     //let user = User::default().with_domain(&domains().await?.any().domain);
+///     //let user = User::default().with_domain(&domains().await?.any_active().ok_or("no active domains")?.domain);
 ///     //let account = create_account(&user).await?;
 ///     //let user = update_token(&user, &token(&user).await?.token);
 ///     //let account = get_account(&user, &account.id.unwrap()).await?;
 ///     Ok(())
 /// }
 /// ```
-pub fn get_account(user: &User, id: &str) -> Result<Account, Error> {
-    accounts::get(&user.email_token, id)
+pub fn get_account(user: &User, id: &str) -> Result<Account, MailTmError> {
+    default_client()?.get_account(user, id)
 }
 
 /// Delete an account
@@ -92,15 +125,15 @@ pub fn get_account(user: &User, id: &str) -> Result<Account, Error> {
 /// use mail_tm_rs::user::User;
 /// use mail_tm_rs::{create_account, update_token, token, delete_account, domains};
 /// fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     //let user = User::default().with_domain(&domains().await?.any().domain);
+///     //let user = User::default().with_domain(&domains().await?.any_active().ok_or("no active domains")?.domain);
 ///     //let account = create_account(&user).await?;
 ///     //let user = update_token(&user, &token(&user).await?.token);
 ///     //delete_account(&user, &account.id.unwrap()).await?;
 ///     Ok(())
 /// }
 /// ```
-pub fn delete_account(user: &User, id: &str) -> Result<(), Error> {
-    accounts::delete(&user.email_token, id)
+pub fn delete_account(user: &User, id: &str) -> Result<(), MailTmError> {
+    default_client()?.delete_account(user, id)
 }
 
 /// Retrieve an account
@@ -113,21 +146,21 @@ pub fn delete_account(user: &User, id: &str) -> Result<(), Error> {
 /// use mail_tm_rs::{create_account, update_token, token, me, domains};
 ///
 /// fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     //let user = User::default().with_domain(&domains().await?.any().domain);
+///     //let user = User::default().with_domain(&domains().await?.any_active().ok_or("no active domains")?.domain);
 ///     //let account = create_account(&user).await?;
 ///     //let user = update_token(&user, &token(&user).await?.token);
 ///     //let user = me(&user).await?;
 ///     Ok(())
 /// }
 /// ```
-pub fn me(user: &User) -> Result<Account, Error> {
-    accounts::me(&user.email_token)
+pub fn me(user: &User) -> Result<Account, MailTmError> {
+    default_client()?.me(user)
 }
 
 /// Retrieve all available domains
 ///
 /// # Example
-/// ```
+/// ```no_run
 /// use mail_tm_rs::user::User;
 /// use mail_tm_rs::{domains};
 ///
@@ -136,8 +169,79 @@ pub fn me(user: &User) -> Result<Account, Error> {
 ///     Ok(())
 /// }
 /// ```
-pub fn domains() -> Result<HydraCollection<Domain>, Error> {
-    domains::domains()
+pub fn domains() -> Result<HydraCollection<Domain>, MailTmError> {
+    default_client()?.domains()
+}
+
+/// Retrieve one page of domains
+///
+/// Unlike [`domains()`], which always returns the first page, this fetches `page` (starting at `1`).
+///
+/// # Example
+/// ```no_run
+/// use mail_tm_rs::list_domains;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let second = list_domains(2)?;
+///     Ok(())
+/// }
+/// ```
+pub fn list_domains(page: usize) -> Result<HydraCollection<Domain>, MailTmError> {
+    default_client()?.list_domains(page)
+}
+
+/// Retrieve a domain by its id
+///
+/// # Example
+/// ```no_run
+/// use mail_tm_rs::{domains, get_domain};
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let first = domains()?.any().ok_or("no domains")?.id2;
+///     let domain = get_domain(&first)?;
+///     Ok(())
+/// }
+/// ```
+pub fn get_domain(id: &str) -> Result<Domain, MailTmError> {
+    default_client()?.get_domain(id)
+}
+
+/// Look up the domain record for a hostname, such as the part of an address after the `@`
+///
+/// Searches every page of `/domains`, returning `None` if the hostname is not served.
+///
+/// # Example
+/// ```no_run
+/// use mail_tm_rs::find_domain;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     if let Some(domain) = find_domain("mail.tm")? {
+///         println!("active: {}", domain.is_active);
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn find_domain(name: &str) -> Result<Option<Domain>, MailTmError> {
+    default_client()?.find_domain(name)
+}
+
+/// Iterate over all domains
+///
+/// Lazily follows the pages of `/domains`, see [`HydraIter`].
+///
+/// # Example
+/// ```no_run
+/// use mail_tm_rs::iter_domains;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     for domain in iter_domains()?.with_max_items(100) {
+///         println!("{}", domain?.domain);
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn iter_domains() -> Result<HydraIter<Domain>, MailTmError> {
+    Ok(default_client()?.iter_domains())
 }
 
 /// List messages
@@ -150,15 +254,37 @@ pub fn domains() -> Result<HydraCollection<Domain>, Error> {
 /// use mail_tm_rs::user::User;
 /// use mail_tm_rs::{create_account, update_token, token, me, list_messages, domains};
 /// fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     //let user = User::default().with_domain(&domains().await?.any().domain);
+///     //let user = User::default().with_domain(&domains().await?.any_active().ok_or("no active domains")?.domain);
 ///     //let account = create_account(&user).await?;
 ///     //let user = update_token(&user, &token(&user).await?.token);
 ///     //let messages = list_messages(&user, Some(33)).await?;
 ///     Ok(())
 /// }
 /// ```
-pub fn list_messages(user: &User, page: Option<usize>) -> Result<HydraCollection<Message>, Error> {
-    messages::messages(&user.email_token, page)
+pub fn list_messages(user: &User, page: Option<usize>) -> Result<HydraCollection<Message>, MailTmError> {
+    default_client()?.list_messages(user, page)
+}
+
+/// Iterate over all messages
+///
+/// Lazily follows the pages of `/messages` belonging to the token holder, see [`HydraIter`].
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::user::User;
+/// use mail_tm_rs::{create_account, update_token, token, iter_messages, domains};
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = User::default().with_domain(&domains()?.any_active().ok_or("no active domains")?.domain);
+///     //let account = create_account(&user)?;
+///     //let user = update_token(&user, &token(&user)?.token);
+///     //for message in iter_messages(&user)? {
+///     //    println!("{}", message?.subject);
+///     //}
+///     Ok(())
+/// }
+/// ```
+pub fn iter_messages(user: &User) -> Result<HydraIter<Message>, MailTmError> {
+    Ok(default_client()?.iter_messages(user))
 }
 
 /// Get message
@@ -170,15 +296,15 @@ pub fn list_messages(user: &User, page: Option<usize>) -> Result<HydraCollection
 /// use mail_tm_rs::user::User;
 /// use mail_tm_rs::{create_account, update_token, token, get_message, domains};
 /// fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     //let user = User::default().with_domain(&domains().await?.any().domain);
+///     //let user = User::default().with_domain(&domains().await?.any_active().ok_or("no active domains")?.domain);
 ///     //let account = create_account(&user).await?;
 ///     //let user = update_token(&user, &token(&user).await?.token);
 ///     //let messages = get_message(&user, "somemessageid").await?;
 ///     Ok(())
 /// }
 /// ```
-pub fn get_message(user: &User, id: &str) -> Result<Message, Error> {
-    messages::get(&user.email_token, id)
+pub fn get_message(user: &User, id: &str) -> Result<Message, MailTmError> {
+    default_client()?.get_message(user, id)
 }
 
 /// Delete message
@@ -191,15 +317,208 @@ pub fn get_message(user: &User, id: &str) -> Result<Message, Error> {
 /// use mail_tm_rs::{create_account, update_token, token, delete_message, domains};
 ///
 /// fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     //let user = User::default().with_domain(&domains().await?.any().domain);
+///     //let user = User::default().with_domain(&domains().await?.any_active().ok_or("no active domains")?.domain);
 ///     //let account = create_account(&user).await?;
 ///     //let user = update_token(&user, &token(&user).await?.token);
 ///     //let messages = delete_message(&user, "somemessageid").await?;
 ///     Ok(())
 /// }
 /// ```
-pub fn delete_message(user: &User, id: &str) -> Result<(), Error> {
-    messages::delete(&user.email_token, id)
+pub fn delete_message(user: &User, id: &str) -> Result<(), MailTmError> {
+    default_client()?.delete_message(user, id)
+}
+
+/// Mark a message as seen
+///
+/// Marks a message by its id as seen, or unseen when `seen` is `false`, and returns the updated message.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::user::User;
+/// use mail_tm_rs::{create_account, update_token, token, mark_seen, domains};
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = User::default().with_domain(&domains()?.any_active().ok_or("no active domains")?.domain);
+///     //let account = create_account(&user)?;
+///     //let user = update_token(&user, &token(&user)?.token);
+///     //let message = mark_seen(&user, "somemessageid", true)?;
+///     Ok(())
+/// }
+/// ```
+pub fn mark_seen(user: &User, id: &str, seen: bool) -> Result<Message, MailTmError> {
+    default_client()?.mark_seen(user, id, seen)
+}
+
+/// Get message source
+///
+/// Retrieve the raw RFC 822 source of a message by its id, headers included.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::user::User;
+/// use mail_tm_rs::{create_account, update_token, token, get_message_source, domains};
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = User::default().with_domain(&domains()?.any_active().ok_or("no active domains")?.domain);
+///     //let account = create_account(&user)?;
+///     //let user = update_token(&user, &token(&user)?.token);
+///     //let source = get_message_source(&user, "somemessageid")?;
+///     Ok(())
+/// }
+/// ```
+pub fn get_message_source(user: &User, id: &str) -> Result<String, MailTmError> {
+    default_client()?.get_message_source(user, id)
+}
+
+/// Download message source
+///
+/// Streams the raw RFC 822 source of a message from its [`Message::download_url`] into `writer`,
+/// returning the number of bytes written. Prefer this over [`get_message_source`] for large messages.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::user::User;
+/// use mail_tm_rs::{create_account, update_token, token, get_message, download_message_source, domains};
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = User::default().with_domain(&domains()?.any_active().ok_or("no active domains")?.domain);
+///     //let account = create_account(&user)?;
+///     //let user = update_token(&user, &token(&user)?.token);
+///     //let message = get_message(&user, "somemessageid")?;
+///     //let file = std::fs::File::create("message.eml")?;
+///     //download_message_source(&user, &message, file)?;
+///     Ok(())
+/// }
+/// ```
+pub fn download_message_source<W: Write>(user: &User, message: &Message, writer: W) -> Result<u64, MailTmError> {
+    default_client()?.download_message_source(user, message, writer)
+}
+
+/// Download an attachment
+///
+/// Streams the contents of a message attachment into `writer`, returning the number of bytes written.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::user::User;
+/// use mail_tm_rs::{create_account, update_token, token, get_message, download_attachment, domains};
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = User::default().with_domain(&domains()?.any_active().ok_or("no active domains")?.domain);
+///     //let account = create_account(&user)?;
+///     //let user = update_token(&user, &token(&user)?.token);
+///     //let message = get_message(&user, "somemessageid")?;
+///     //for attachment in &message.attachments {
+///     //    let file = std::fs::File::create(&attachment.filename)?;
+///     //    download_attachment(&user, attachment, file)?;
+///     //}
+///     Ok(())
+/// }
+/// ```
+pub fn download_attachment<W: Write>(user: &User, attachment: &Attachment, writer: W) -> Result<u64, MailTmError> {
+    default_client()?.download_attachment(user, attachment, writer)
+}
+
+/// Get an attachment
+///
+/// Retrieves the contents of a message attachment into memory.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::user::User;
+/// use mail_tm_rs::{create_account, update_token, token, get_message, get_attachment, domains};
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = User::default().with_domain(&domains()?.any_active().ok_or("no active domains")?.domain);
+///     //let account = create_account(&user)?;
+///     //let user = update_token(&user, &token(&user)?.token);
+///     //let message = get_message(&user, "somemessageid")?;
+///     //let bytes = get_attachment(&user, &message.attachments[0])?;
+///     Ok(())
+/// }
+/// ```
+pub fn get_attachment(user: &User, attachment: &Attachment) -> Result<Vec<u8>, MailTmError> {
+    default_client()?.get_attachment(user, attachment)
+}
+
+/// Wait for a message
+///
+/// Polls the inbox of the token holder, backing off between polls, until a message matching the
+/// predicate arrives. Each new message is fetched in full before being handed to the predicate, so
+/// it can check the sender, subject or body. Messages already checked are skipped, as are messages
+/// deleted before they could be fetched. Each poll follows the listing onto older pages until it
+/// reaches a page with nothing new, so a busy inbox does not push a match out of sight.
+/// Rate limits, server errors and failed connections are logged and polled through, backing off as
+/// usual. Fails with [`error::MailTmError::Timeout`] if nothing matches within `timeout`, or with
+/// any other error straight away.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+/// use mail_tm_rs::user::User;
+/// use mail_tm_rs::{create_account, update_token, token, wait_for_message, domains};
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = User::default().with_domain(&domains()?.any_active().ok_or("no active domains")?.domain);
+///     //let account = create_account(&user)?;
+///     //let user = update_token(&user, &token(&user)?.token);
+///     //let message = wait_for_message(
+///     //    &user,
+///     //    |message| message.from.address == "noreply@example.com" && message.subject.contains("Verify"),
+///     //    Duration::from_secs(120),
+///     //)?;
+///     Ok(())
+/// }
+/// ```
+pub fn wait_for_message<P: FnMut(&Message) -> bool>(user: &User, predicate: P, timeout: Duration) -> Result<Message, MailTmError> {
+    default_client()?.wait_for_message(user, predicate, timeout)
+}
+
+/// Subscribe to real time updates
+///
+/// Opens a Server-Sent Events stream on the Mercure hub for the account with the given id, yielding
+/// new and updated messages as well as account changes as they happen. Dropped connections are
+/// reopened automatically, resuming from the last event seen.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::user::User;
+/// use mail_tm_rs::{create_account, update_token, token, subscribe, domains};
+/// use mail_tm_rs::events::Update;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = User::default().with_domain(&domains()?.any_active().ok_or("no active domains")?.domain);
+///     //let account = create_account(&user)?;
+///     //let user = update_token(&user, &token(&user)?.token);
+///     //for event in subscribe(&user, &account.id.unwrap())? {
+///     //    if let Update::Message(message) = event?.update {
+///     //        println!("{}", message.subject);
+///     //    }
+///     //}
+///     Ok(())
+/// }
+/// ```
+pub fn subscribe(user: &User, account_id: &str) -> Result<Subscription, MailTmError> {
+    Ok(default_client()?.subscribe(user, account_id))
+}
+
+/// Log in with an email address and password
+///
+/// Retrieves a token for an existing account and returns a [`User`] with its email token populated,
+/// ready to be used with every other call. Fails with [`error::MailTmError::InvalidCredentials`] if the
+/// address or password is wrong.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::login;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     //let user = login("someone@example.com", "hunter2")?;
+///     Ok(())
+/// }
+/// ```
+pub fn login(address: &str, password: &str) -> Result<User, MailTmError> {
+    default_client()?.login(address, password)
 }
 
 /// Retrieve a token for a user
@@ -208,19 +527,19 @@ pub fn delete_message(user: &User, id: &str) -> Result<(), Error> {
 /// providing a raw token or a user.
 ///
 /// # Example
-/// ```
+/// ```no_run
 /// use mail_tm_rs::user::User;
 /// use mail_tm_rs::{create_account, update_token, token, domains};
 ///
 /// fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     let user = User::default().with_domain(&domains()?.any().domain);
+///     let user = User::default().with_domain(&domains()?.any_active().ok_or("no active domains")?.domain);
 ///     let account = create_account(&user)?;
 ///     let user = update_token(&user, &token(&user)?.token);
 ///     Ok(())
 /// }
 /// ```
-pub fn token(user: &User) -> Result<Token, Error> {
-    token::token(user)
+pub fn token(user: &User) -> Result<Token, MailTmError> {
+    default_client()?.token(user)
 }
 
 /// Populates the email token on a user
@@ -228,12 +547,12 @@ pub fn token(user: &User) -> Result<Token, Error> {
 /// This uses a simple builder like pattern. In the future we will support a zero-copy version too.
 ///
 /// # Example
-/// ```
+/// ```no_run
 /// use mail_tm_rs::user::User;
 /// use mail_tm_rs::{create_account, update_token, token, domains};
 ///
 /// fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     let user = User::default().with_domain(&domains()?.any().domain);
+///     let user = User::default().with_domain(&domains()?.any_active().ok_or("no active domains")?.domain);
 ///     let account = create_account(&user)?;
 ///     let user = update_token(&user, &token(&user)?.token);
 ///     Ok(())
@@ -245,9 +564,3 @@ pub fn update_token(user: &User, token: &str) -> User {
         ..user.clone()
     }
 }
-
-macro_rules! bo {
-  ($e:expr) => {
-    tokio_test::block_on($e)
-  };
-}
\ No newline at end of file
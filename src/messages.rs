@@ -1,10 +1,19 @@
-use std::io::Read;
-use anyhow::{Context, Error};
+use std::collections::HashSet;
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "async")]
+use futures_lite::AsyncWrite;
 use serde::{Deserialize, Serialize};
 
-use crate::{http, MAIL_API_URL};
-use crate::http::{Client, get_headers};
+use crate::client::MailTmClient;
+use crate::error::MailTmError;
+use crate::http::header::CONTENT_TYPE;
+use crate::http::{Method, Request};
 use crate::hydra::{HydraCollection, Search, View};
+use crate::timestamp::Timestamp;
+use crate::user::User;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -19,10 +28,15 @@ pub struct Messages {
     pub search: Option<Search>,
 }
 
+/// A message as the api lists or returns it
+///
+/// Listings only carry a summary, the body, attachments and other details default to empty there
+/// and are filled in by [`crate::get_message`]. The snake case names of earlier versions are still
+/// accepted when deserializing.
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Message {
-    #[serde(rename = "@context")]
+    #[serde(rename = "@context", default)]
     pub context: String,
     #[serde(rename = "@id")]
     pub id: String,
@@ -30,34 +44,67 @@ pub struct Message {
     pub type_field: String,
     #[serde(rename = "id")]
     pub id2: String,
-    #[serde(rename = "account_id")]
+    #[serde(alias = "account_id")]
     pub account_id: String,
     #[serde(rename = "msgid")]
     pub msg_id: String,
     pub from: From,
     pub to: Vec<To>,
+    #[serde(default)]
     pub cc: Vec<::serde_json::Value>,
+    #[serde(default)]
     pub bcc: Vec<::serde_json::Value>,
     pub subject: String,
+    /// The start of the text, only sent in listings
+    #[serde(default)]
+    pub intro: String,
     pub seen: bool,
+    #[serde(default)]
     pub flagged: bool,
-    #[serde(rename = "verification_results")]
+    #[serde(rename = "verifications", alias = "verification_results", default)]
     pub verification_results: Vec<::serde_json::Value>,
+    #[serde(default)]
     pub retention: bool,
-    #[serde(rename = "retention_date")]
-    pub retention_date: i64,
+    #[serde(alias = "retention_date", default)]
+    pub retention_date: Option<Timestamp>,
+    #[serde(default)]
     pub text: String,
+    #[serde(default)]
     pub html: Vec<String>,
-    #[serde(rename = "has_attachments")]
+    #[serde(alias = "has_attachments")]
     pub has_attachments: bool,
-    pub attachments: Vec<::serde_json::Value>,
-    #[serde(rename = "download_url")]
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    #[serde(alias = "download_url")]
     pub download_url: String,
     pub size: i64,
-    #[serde(rename = "created_at")]
-    pub created_at: String,
-    #[serde(rename = "updated_at")]
-    pub updated_at: String,
+    #[serde(alias = "created_at")]
+    pub created_at: Timestamp,
+    #[serde(alias = "updated_at")]
+    pub updated_at: Timestamp,
+}
+
+impl Message {
+    /// How long ago the message was received
+    pub fn age(&self) -> Duration {
+        self.created_at.age()
+    }
+}
+
+/// A file attached to a [`Message`]
+///
+/// Its contents can be fetched with [`crate::download_attachment`] or [`crate::get_attachment`].
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Attachment {
+    pub id: String,
+    pub filename: String,
+    pub content_type: String,
+    pub disposition: String,
+    pub transfer_encoding: String,
+    pub related: bool,
+    pub size: i64,
+    pub download_url: String,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -74,107 +121,404 @@ pub struct To {
     pub name: String,
 }
 
-pub(crate) fn messages(token: &str, page: Option<usize>) -> Result<HydraCollection<Message>, Error> {
-    let client = Client::new()?.with_auth(&token)?.build()?;
-
-    log::debug!("Getting messages");
-
-    let builder = format!("{}/messages", MAIL_API_URL.to_owned());
-    let builder = if let Some(idx) = page {
-        builder + &format!("?page={}", idx)
+fn messages_request(client: &MailTmClient, token: &str, page: Option<usize>) -> Result<Request<String>, MailTmError> {
+    let path = if let Some(idx) = page {
+        format!("/messages?page={}", idx)
     } else {
-        builder
+        "/messages".to_string()
     };
 
-    let mut response = client
-        .get(&builder)?;
+    Ok(client
+        .request(Method::GET, &path, Some(token))
+        .body(String::new())?)
+}
 
-    let code = response.status();
+fn get_request(client: &MailTmClient, token: &str, id: &str) -> Result<Request<String>, MailTmError> {
+    Ok(client
+        .request(Method::GET, &format!("/messages/{}", id), Some(token))
+        .body(String::new())?)
+}
 
-    let response = {
-        let mut buffer = String::new();
-        response.body_mut().read_to_string(&mut buffer)?;
-        buffer
-    };
+fn delete_request(client: &MailTmClient, token: &str, id: &str) -> Result<Request<String>, MailTmError> {
+    Ok(client
+        .request(Method::DELETE, &format!("/messages/{}", id), Some(token))
+        .body(String::new())?)
+}
 
-    http::check_response_status(&code, &response)?;
+fn patch_request(client: &MailTmClient, token: &str, id: &str, seen: bool) -> Result<Request<String>, MailTmError> {
+    let patch = serde_json::json!({ "seen": seen });
+    Ok(client
+        .request(Method::PATCH, &format!("/messages/{}", id), Some(token))
+        .header(CONTENT_TYPE, "application/merge-patch+json")
+        .body(patch.to_string())?)
+}
 
-    log::trace!("Retrieved domains: {}", response);
-    Ok(serde_json::from_str(&response)?)
+fn attachment_request(client: &MailTmClient, token: &str, attachment: &Attachment) -> Result<Request<String>, MailTmError> {
+    Ok(client
+        .request(Method::GET, &attachment.download_url, Some(token))
+        .body(String::new())?)
 }
 
-pub(crate) fn get(token: &str, id: &str) -> Result<Message, Error> {
-    let client = Client::new()?.with_auth(&token)?.build()?;
+pub(crate) fn messages(client: &MailTmClient, token: &str, page: Option<usize>) -> Result<HydraCollection<Message>, MailTmError> {
+    log::debug!("Getting messages");
+    client.call(messages_request(client, token, page)?)
+}
 
+pub(crate) fn get(client: &MailTmClient, token: &str, id: &str) -> Result<Message, MailTmError> {
     log::debug!("Searching for message with id {}", id);
+    client.call(get_request(client, token, id)?)
+}
 
+pub(crate) fn delete(client: &MailTmClient, token: &str, id: &str) -> Result<(), MailTmError> {
+    log::debug!("Deleting message with id {}", id);
+    client.execute(delete_request(client, token, id)?)?;
+    Ok(())
+}
 
-    let mut response = client
-        .get(&format!("{}/messages/{}", MAIL_API_URL.to_owned(), id))?;
-
-    let code = response.status();
+pub(crate) fn patch(client: &MailTmClient, token: &str, id: &str, seen: bool) -> Result<Message, MailTmError> {
+    log::debug!("Marking message with id {} as seen: {}", id, seen);
+    client.call(patch_request(client, token, id, seen)?)
+}
 
-    let response = {
-        let mut buffer = String::new();
-        response.body_mut().read_to_string(&mut buffer)?;
-        buffer
-    };
+pub(crate) fn download_attachment<W: Write>(client: &MailTmClient, token: &str, attachment: &Attachment, writer: W) -> Result<u64, MailTmError> {
+    log::debug!("Downloading attachment {} from {}", attachment.filename, attachment.download_url);
+    client.download(attachment_request(client, token, attachment)?, writer)
+}
 
-    http::check_response_status(&code, &response)?;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(10);
 
-    log::trace!("Retrieved a message: {}", response);
-    Ok(serde_json::from_str(&response)?)
+/// Polling state for [`wait_for`], tracks the ids already checked and backs off between polls
+struct Poll {
+    seen: HashSet<String>,
+    interval: Duration,
+    timeout: Duration,
+    deadline: Instant,
 }
 
+impl Poll {
+    fn new(timeout: Duration) -> Poll {
+        Poll {
+            seen: HashSet::new(),
+            interval: POLL_INTERVAL,
+            timeout,
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    /// Ids in the listing that have not been checked yet
+    fn unseen(&self, listing: HydraCollection<Message>) -> Vec<String> {
+        listing
+            .members
+            .into_iter()
+            .map(|message| message.id2)
+            .filter(|id| !self.seen.contains(id))
+            .collect()
+    }
+
+    /// Marks a message as checked once it was fetched, or found to be deleted
+    fn checked(&mut self, id: String) {
+        self.seen.insert(id);
+    }
+
+    /// Logs a transient error so polling carries on, hands any other error back
+    fn recover(&self, err: MailTmError) -> Result<(), MailTmError> {
+        if !err.is_transient() {
+            return Err(err);
+        }
+        log::debug!("Polling for messages failed, trying again: {}", err);
+        Ok(())
+    }
+
+    /// How long to sleep before polling again, or a timeout error once the deadline has passed
+    fn next_delay(&mut self) -> Result<Duration, MailTmError> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(MailTmError::Timeout(self.timeout));
+        }
+        let delay = self.interval.min(remaining);
+        self.interval = (self.interval * 2).min(MAX_POLL_INTERVAL);
+        Ok(delay)
+    }
+}
 
-pub(crate) fn delete(token: &str, id: &str) -> Result<(), Error> {
-    let client = Client::new()?.with_auth(&token)?.build()?;
+pub(crate) fn wait_for<P: FnMut(&Message) -> bool>(client: &MailTmClient, user: &User, mut predicate: P, timeout: Duration) -> Result<Message, MailTmError> {
+    log::debug!("Waiting up to {:?} for a matching message", timeout);
+    let mut poll = Poll::new(timeout);
+    loop {
+        match check_new(client, user, &mut poll, &mut predicate) {
+            Ok(Some(message)) => return Ok(message),
+            Ok(None) => {}
+            Err(err) => poll.recover(err)?,
+        }
+        thread::sleep(poll.next_delay()?);
+    }
+}
 
-    log::debug!("Searching for account with id {}", id);
+/// Checks the messages not checked yet, following older pages until one has nothing new
+fn check_new<P: FnMut(&Message) -> bool>(client: &MailTmClient, user: &User, poll: &mut Poll, predicate: &mut P) -> Result<Option<Message>, MailTmError> {
+    let mut page = None;
+    loop {
+        let listing = client.list_messages(user, page)?;
+        let next = listing.view.as_ref().and_then(View::next_page);
+        let unseen = poll.unseen(listing);
+        if unseen.is_empty() {
+            return Ok(None);
+        }
+        for id in unseen {
+            match client.get_message(user, &id) {
+                Ok(message) => {
+                    poll.checked(id);
+                    if predicate(&message) {
+                        return Ok(Some(message));
+                    }
+                }
+                Err(MailTmError::NotFound(_)) => {
+                    log::debug!("Message {} was deleted before it could be checked", id);
+                    poll.checked(id);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        page = match next {
+            Some(next) => Some(next),
+            None => return Ok(None),
+        };
+    }
+}
 
+#[cfg(feature = "async")]
+pub(crate) async fn wait_for_async<P: FnMut(&Message) -> bool>(client: &MailTmClient, user: &User, mut predicate: P, timeout: Duration) -> Result<Message, MailTmError> {
+    log::debug!("Waiting up to {:?} for a matching message", timeout);
+    let mut poll = Poll::new(timeout);
+    loop {
+        match check_new_async(client, user, &mut poll, &mut predicate).await {
+            Ok(Some(message)) => return Ok(message),
+            Ok(None) => {}
+            Err(err) => poll.recover(err)?,
+        }
+        futures_timer::Delay::new(poll.next_delay()?).await;
+    }
+}
 
-    let response = client
-        .delete(&format!("{}/messages/{}", MAIL_API_URL.to_owned(), id))?;
+#[cfg(feature = "async")]
+async fn check_new_async<P: FnMut(&Message) -> bool>(client: &MailTmClient, user: &User, poll: &mut Poll, predicate: &mut P) -> Result<Option<Message>, MailTmError> {
+    let mut page = None;
+    loop {
+        let listing = client.list_messages_async(user, page).await?;
+        let next = listing.view.as_ref().and_then(View::next_page);
+        let unseen = poll.unseen(listing);
+        if unseen.is_empty() {
+            return Ok(None);
+        }
+        for id in unseen {
+            match client.get_message_async(user, &id).await {
+                Ok(message) => {
+                    poll.checked(id);
+                    if predicate(&message) {
+                        return Ok(Some(message));
+                    }
+                }
+                Err(MailTmError::NotFound(_)) => {
+                    log::debug!("Message {} was deleted before it could be checked", id);
+                    poll.checked(id);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        page = match next {
+            Some(next) => Some(next),
+            None => return Ok(None),
+        };
+    }
+}
 
-    let code = response.status();
+#[cfg(feature = "async")]
+pub(crate) async fn messages_async(client: &MailTmClient, token: &str, page: Option<usize>) -> Result<HydraCollection<Message>, MailTmError> {
+    log::debug!("Getting messages");
+    client.call_async(messages_request(client, token, page)?).await
+}
 
-    http::check_response_status(&code, "")?;
+#[cfg(feature = "async")]
+pub(crate) async fn get_async(client: &MailTmClient, token: &str, id: &str) -> Result<Message, MailTmError> {
+    log::debug!("Searching for message with id {}", id);
+    client.call_async(get_request(client, token, id)?).await
+}
 
-    log::trace!("Deleted user with id {}", id);
+#[cfg(feature = "async")]
+pub(crate) async fn delete_async(client: &MailTmClient, token: &str, id: &str) -> Result<(), MailTmError> {
+    log::debug!("Deleting message with id {}", id);
+    client.execute_async(delete_request(client, token, id)?).await?;
     Ok(())
 }
 
-// TODO impl me
-pub(crate) fn patch(token: &str, id: &str) -> Result<(), Error> {
-    let client = Client::new()?.with_auth(&token)?.build()?;
+#[cfg(feature = "async")]
+pub(crate) async fn patch_async(client: &MailTmClient, token: &str, id: &str, seen: bool) -> Result<Message, MailTmError> {
+    log::debug!("Marking message with id {} as seen: {}", id, seen);
+    client.call_async(patch_request(client, token, id, seen)?).await
+}
 
-    Ok(())
+#[cfg(feature = "async")]
+pub(crate) async fn download_attachment_async<W: AsyncWrite + Unpin>(client: &MailTmClient, token: &str, attachment: &Attachment, writer: W) -> Result<u64, MailTmError> {
+    log::debug!("Downloading attachment {} from {}", attachment.filename, attachment.download_url);
+    client.download_async(attachment_request(client, token, attachment)?, writer).await
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::accounts;
-    use crate::accounts::create;
-    use crate::user::User;
+    use crate::inbox::TempInbox;
+    use crate::mock::{Incoming, MockServer};
 
     use super::*;
 
-    async fn test_messages() -> Result<(), Error> {
+    #[test]
+    fn test_messages() -> Result<(), MailTmError> {
         pretty_env_logger::try_init().ok();
-        let user = User::default().with_domain(&crate::domains::domains()?.any().domain);
-        let create = create(&user).unwrap();
-        let token = crate::token(&user).unwrap();
+        let server = MockServer::start()?;
+        let inbox = TempInbox::with_client(&server.client()?)?;
 
-
-        let messages = messages(&token.token, None)?;
+        let messages = messages(inbox.client(), &inbox.user().email_token, None)?;
         assert_eq!(messages.total_items, 0);
 
-        let id = create.id.unwrap();
+        let sent = server.deliver(inbox.address(), Incoming::new("someone@example.com", "Welcome"))?;
+        let received = wait_for(inbox.client(), inbox.user(), |message| message.subject == "Welcome", Duration::from_secs(5))?;
+        assert_eq!(received, sent);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_for_older_pages() -> Result<(), MailTmError> {
+        let server = MockServer::start()?;
+        let inbox = TempInbox::with_client(&server.client()?)?;
+        let oldest = server.deliver(inbox.address(), Incoming::new("someone@example.com", "Oldest"))?;
+        for idx in 0..30 {
+            server.deliver(inbox.address(), Incoming::new("someone@example.com", &format!("Newer {}", idx)))?;
+        }
+
+        let received = wait_for(inbox.client(), inbox.user(), |message| message.subject == "Oldest", Duration::from_secs(5))?;
+        assert_eq!(received, oldest);
+        Ok(())
+    }
+
+    fn message_json(id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "@id": format!("/messages/{}", id), "@type": "Message", "id": id, "accountId": "/accounts/a", "msgid": "<a@mock.tm>",
+            "from": {"address": "a@example.com", "name": ""}, "to": [], "subject": id, "seen": false,
+            "hasAttachments": false, "downloadUrl": format!("/messages/{}/download", id), "size": 1,
+            "createdAt": "2024-01-01T00:00:00+00:00", "updatedAt": "2024-01-01T00:00:00+00:00",
+        })
+    }
+
+    fn listing_json(ids: &[&str]) -> String {
+        let members: Vec<_> = ids.iter().map(|id| message_json(id)).collect();
+        serde_json::json!({"hydra:member": members, "hydra:totalItems": ids.len()}).to_string()
+    }
+
+    fn memory_client(transport: &crate::transport::MemoryTransport) -> Result<MailTmClient, MailTmError> {
+        MailTmClient::builder()?
+            .with_base_url("http://localhost")
+            .with_transport(transport.clone())
+            .with_token_refresh(false)
+            .build()
+    }
 
-        accounts::delete(&token.token, &id).unwrap();
+    #[test]
+    fn test_wait_for_deleted_message() -> Result<(), MailTmError> {
+        let transport = crate::transport::MemoryTransport::with_handler(|request| {
+            let (status, body) = match request.uri.as_str() {
+                "http://localhost/messages" => (200, listing_json(&["gone", "kept"])),
+                "http://localhost/messages/kept" => (200, message_json("kept").to_string()),
+                _ => (404, r#"{"hydra:description":"Not Found"}"#.to_string()),
+            };
+            let mut response = crate::http::Response::new(body.into_bytes());
+            *response.status_mut() = crate::http::StatusCode::from_u16(status).unwrap();
+            response
+        });
+        let user = crate::update_token(&User::default(), "token");
+
+        let received = wait_for(&memory_client(&transport)?, &user, |_| true, Duration::from_secs(5))?;
+        assert_eq!(received.id2, "kept");
+        Ok(())
+    }
 
+    #[test]
+    fn test_wait_for_rate_limited() -> Result<(), MailTmError> {
+        let transport = crate::transport::MemoryTransport::new();
+        transport.push_response(429, r#"{"code":429,"message":"Too many requests"}"#);
+        transport.push_response(200, &listing_json(&["late"]));
+        transport.push_response(429, r#"{"code":429,"message":"Too many requests"}"#);
+        transport.push_response(200, &listing_json(&["late"]));
+        transport.push_response(200, &message_json("late").to_string());
+        let user = crate::update_token(&User::default(), "token");
+
+        let received = wait_for(&memory_client(&transport)?, &user, |_| true, Duration::from_secs(10))?;
+        assert_eq!(received.id2, "late");
+        assert_eq!(transport.requests().len(), 5);
+
+        transport.push_response(401, r#"{"code":401,"message":"Invalid JWT Token"}"#);
+        let err = wait_for(&memory_client(&transport)?, &user, |_| true, Duration::from_secs(10)).unwrap_err();
+        assert!(matches!(err, MailTmError::Unauthorized(_)), "{:?}", err);
         Ok(())
     }
 
+    #[test]
+    fn test_patch_request() -> Result<(), MailTmError> {
+        let client = MailTmClient::builder()?.with_base_url("http://localhost").build()?;
+        let request = patch_request(&client, "token", "abc", true)?;
+
+        assert_eq!(request.method(), Method::PATCH);
+        assert_eq!(request.uri(), "http://localhost/messages/abc");
+        assert_eq!(request.headers().get(CONTENT_TYPE).unwrap(), "application/merge-patch+json");
+        assert_eq!(request.body(), r#"{"seen":true}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn test_attachment_deserialize() -> Result<(), serde_json::Error> {
+        let attachment: Attachment = serde_json::from_str(r#"{
+            "id": "ATTACH000001",
+            "filename": "invoice.pdf",
+            "contentType": "application/pdf",
+            "disposition": "attachment",
+            "transferEncoding": "base64",
+            "related": false,
+            "size": 1024,
+            "downloadUrl": "/messages/abc/attachment/ATTACH000001"
+        }"#)?;
+
+        assert_eq!(attachment.filename, "invoice.pdf");
+        assert_eq!(attachment.content_type, "application/pdf");
+        assert_eq!(attachment.transfer_encoding, "base64");
+        assert_eq!(attachment.download_url, "/messages/abc/attachment/ATTACH000001");
+        Ok(())
+    }
+
+    #[test]
+    fn test_poll() {
+        let listing = |ids: &[&str]| HydraCollection {
+            members: ids
+                .iter()
+                .map(|id| Message { id2: id.to_string(), ..Message::default() })
+                .collect(),
+            ..HydraCollection::default()
+        };
+
+        let mut poll = Poll::new(Duration::from_secs(60));
+        assert_eq!(poll.unseen(listing(&["a", "b"])), vec!["a", "b"]);
+        poll.checked("a".to_string());
+        assert_eq!(poll.unseen(listing(&["c", "a", "b"])), vec!["c", "b"]);
+
+        assert_eq!(poll.next_delay().unwrap(), POLL_INTERVAL);
+        assert_eq!(poll.next_delay().unwrap(), POLL_INTERVAL * 2);
+        for _ in 0..10 {
+            poll.next_delay().unwrap();
+        }
+        assert_eq!(poll.next_delay().unwrap(), MAX_POLL_INTERVAL);
+
+        let mut poll = Poll::new(Duration::ZERO);
+        let err = poll.next_delay().unwrap_err();
+        assert!(matches!(err, MailTmError::Timeout(_)));
+    }
+
     //TODO other tests
 }
@@ -0,0 +1,790 @@
+//! An in-process Mail-TM server for offline testing
+//!
+//! Enabled with the `mock` feature. [`MockServer`] listens on a random local port and implements
+//! the endpoints this crate talks to (`/domains`, `/accounts`, `/token`, `/me`, `/messages` and
+//! `/sources`) on top of in-memory state. Mail is injected with [`MockServer::deliver`].
+//!
+//! The Mercure hub is not mocked, so [`crate::MailTmClient::subscribe`] has nothing to connect to.
+//!
+//! # Example
+//! ```
+//! use mail_tm_rs::mock::{Incoming, MockServer};
+//! use mail_tm_rs::inbox::TempInbox;
+//!
+//! fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let server = MockServer::start()?;
+//!     let inbox = TempInbox::with_client(&server.client()?)?;
+//!
+//!     server.deliver(inbox.address(), Incoming::new("sender@example.com", "Hello").with_text("Hi there"))?;
+//!     assert_eq!(inbox.messages(None)?.members[0].subject, "Hello");
+//!     Ok(())
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::accounts::Account;
+use crate::client::MailTmClient;
+use crate::domains::Domain;
+use crate::error::MailTmError;
+use crate::messages::Message;
+use crate::timestamp::Timestamp;
+
+const PAGE_SIZE: usize = 30;
+const DEFAULT_DOMAIN: &str = "mock.tm";
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// A mail about to arrive in a mock inbox, see [`MockServer::deliver`]
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Incoming {
+    pub from: String,
+    pub from_name: String,
+    pub subject: String,
+    pub text: String,
+    pub html: Vec<String>,
+    pub attachments: Vec<IncomingAttachment>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct IncomingAttachment {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Incoming {
+    pub fn new(from: &str, subject: &str) -> Incoming {
+        Incoming {
+            from: from.to_string(),
+            subject: subject.to_string(),
+            ..Incoming::default()
+        }
+    }
+
+    pub fn with_from_name(self, from_name: &str) -> Incoming {
+        Incoming {
+            from_name: from_name.to_string(),
+            ..self
+        }
+    }
+
+    pub fn with_text(self, text: &str) -> Incoming {
+        Incoming {
+            text: text.to_string(),
+            ..self
+        }
+    }
+
+    pub fn with_html(mut self, html: &str) -> Incoming {
+        self.html.push(html.to_string());
+        self
+    }
+
+    pub fn with_attachment(mut self, filename: &str, content_type: &str, data: &[u8]) -> Incoming {
+        self.attachments.push(IncomingAttachment {
+            filename: filename.to_string(),
+            content_type: content_type.to_string(),
+            data: data.to_vec(),
+        });
+        self
+    }
+}
+
+struct StoredDomain {
+    id: String,
+    domain: String,
+    is_active: bool,
+    created_at: Timestamp,
+}
+
+struct StoredAccount {
+    id: String,
+    address: String,
+    password: String,
+    created_at: Timestamp,
+}
+
+struct StoredMessage {
+    id: String,
+    account_id: String,
+    to: String,
+    incoming: Incoming,
+    seen: bool,
+    source: String,
+    created_at: Timestamp,
+    updated_at: Timestamp,
+}
+
+#[derive(Default)]
+struct State {
+    domains: Vec<StoredDomain>,
+    accounts: Vec<StoredAccount>,
+    messages: Vec<StoredMessage>,
+    /// Issued tokens and the account and expiry (unix seconds) they belong to
+    tokens: HashMap<String, (String, u64)>,
+    token_lifetime: Duration,
+}
+
+/// A mock Mail-TM api served from a background thread
+///
+/// Responses are written out in the json shape the public api documents rather than serialized
+/// from this crate's types, so they also check that those types decode real responses.
+///
+/// The server shuts down when dropped.
+pub struct MockServer {
+    url: String,
+    server: Arc<Server>,
+    state: Arc<Mutex<State>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MockServer {
+    /// Starts a server on a random local port with the single active domain `mock.tm`
+    pub fn start() -> Result<MockServer, MailTmError> {
+        let server = Server::http("127.0.0.1:0").map_err(io::Error::other)?;
+        let address = server
+            .server_addr()
+            .to_ip()
+            .ok_or_else(|| io::Error::other("mock server is not listening on an ip address"))?;
+        let server = Arc::new(server);
+        let state = Arc::new(Mutex::new(State {
+            token_lifetime: DEFAULT_TOKEN_LIFETIME,
+            ..State::default()
+        }));
+
+        let handle = {
+            let (server, state) = (server.clone(), state.clone());
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(&state, request);
+                }
+            })
+        };
+
+        let mock = MockServer {
+            url: format!("http://{}", address),
+            server,
+            state,
+            handle: Some(handle),
+        };
+        mock.add_domain(DEFAULT_DOMAIN, true);
+        log::debug!("Mock server listening on {}", mock.url);
+        Ok(mock)
+    }
+
+    /// The base url of the server, such as `http://127.0.0.1:4321`
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// A client pointed at this server
+    pub fn client(&self) -> Result<MailTmClient, MailTmError> {
+        MailTmClient::builder()?
+            .with_base_url(&self.url)
+            .with_mercure_url(&format!("{}/.well-known/mercure", self.url))
+            .build()
+    }
+
+    /// Serves another domain
+    pub fn add_domain(&self, name: &str, is_active: bool) -> Domain {
+        let domain = StoredDomain {
+            id: random_id(),
+            domain: name.to_string(),
+            is_active,
+            created_at: Timestamp::now(),
+        };
+        let json = domain_json(&domain);
+        self.state().domains.push(domain);
+        decode(json)
+    }
+
+    /// How long issued tokens stay valid, an hour by default
+    pub fn set_token_lifetime(&self, lifetime: Duration) {
+        self.state().token_lifetime = lifetime;
+    }
+
+    /// Every account currently registered
+    pub fn accounts(&self) -> Vec<Account> {
+        self.state().accounts.iter().map(|account| decode(account_json(account))).collect()
+    }
+
+    /// The messages in the inbox of `address`, newest first, with all their details
+    pub fn messages(&self, address: &str) -> Vec<Message> {
+        let state = self.state();
+        match state.account_by_address(address) {
+            Some(account) => state.inbox(&account.id).map(|message| decode(message_json(message))).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Drops a new message into the inbox of `to`
+    pub fn deliver(&self, to: &str, incoming: Incoming) -> Result<Message, MailTmError> {
+        let mut state = self.state();
+        let account_id = state
+            .account_by_address(to)
+            .map(|account| account.id.clone())
+            .ok_or_else(|| MailTmError::InvalidAddress(to.to_string()))?;
+
+        let id = random_id();
+        let now = Timestamp::now();
+        let message = StoredMessage {
+            source: rfc822(to, &id, &incoming, now),
+            id,
+            account_id,
+            to: to.to_string(),
+            incoming,
+            seen: false,
+            created_at: now,
+            updated_at: now,
+        };
+
+        log::debug!("Delivering message {} to {}", message.id, to);
+        let json = message_json(&message);
+        state.messages.push(message);
+        Ok(decode(json))
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Reads a fixture back into the crate's own types
+fn decode<T: DeserializeOwned>(json: Value) -> T {
+    serde_json::from_value(json).expect("mock fixture matches the crate's types")
+}
+
+impl State {
+    fn account_by_address(&self, address: &str) -> Option<&StoredAccount> {
+        self.accounts
+            .iter()
+            .find(|account| account.address.eq_ignore_ascii_case(address))
+    }
+
+    fn inbox<'a>(&'a self, account_id: &'a str) -> impl Iterator<Item = &'a StoredMessage> + 'a {
+        self.messages
+            .iter()
+            .rev()
+            .filter(move |message| message.account_id == account_id)
+    }
+
+    fn message(&self, account_id: &str, id: &str) -> Option<usize> {
+        self.messages
+            .iter()
+            .position(|message| message.account_id == account_id && message.id == id)
+    }
+
+    fn issue_token(&mut self, account_id: &str, address: &str) -> String {
+        let iat = now();
+        let exp = iat + self.token_lifetime.as_secs();
+        let claims = json!({
+            "iat": iat,
+            "exp": exp,
+            "roles": ["ROLE_USER"],
+            "username": address,
+            "id": account_id,
+        });
+        let token = format!(
+            "{}.{}.{}",
+            URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"none"}"#),
+            URL_SAFE_NO_PAD.encode(claims.to_string()),
+            random_id()
+        );
+        self.tokens.insert(token.clone(), (account_id.to_string(), exp));
+        token
+    }
+}
+
+// The shapes below follow the api documentation at https://docs.mail.tm
+
+fn domain_json(domain: &StoredDomain) -> Value {
+    json!({
+        "@context": "/contexts/Domain",
+        "@id": format!("/domains/{}", domain.id),
+        "@type": "Domain",
+        "id": domain.id,
+        "domain": domain.domain,
+        "isActive": domain.is_active,
+        "isPrivate": false,
+        "createdAt": domain.created_at.to_string(),
+        "updatedAt": domain.created_at.to_string(),
+    })
+}
+
+fn account_json(account: &StoredAccount) -> Value {
+    json!({
+        "@context": "/contexts/Account",
+        "@id": format!("/accounts/{}", account.id),
+        "@type": "Account",
+        "id": account.id,
+        "address": account.address,
+        "quota": 40_000_000,
+        "used": 0,
+        "isDisabled": false,
+        "isDeleted": false,
+        "createdAt": account.created_at.to_string(),
+        "updatedAt": account.created_at.to_string(),
+    })
+}
+
+/// The summary served by `/messages`
+fn message_summary_json(message: &StoredMessage) -> Value {
+    json!({
+        "@id": format!("/messages/{}", message.id),
+        "@type": "Message",
+        "id": message.id,
+        "accountId": format!("/accounts/{}", message.account_id),
+        "msgid": format!("<{}@{}>", message.id, DEFAULT_DOMAIN),
+        "from": { "address": message.incoming.from, "name": message.incoming.from_name },
+        "to": [{ "address": message.to, "name": "" }],
+        "subject": message.incoming.subject,
+        "intro": message.incoming.text.chars().take(120).collect::<String>(),
+        "seen": message.seen,
+        "isDeleted": false,
+        "hasAttachments": !message.incoming.attachments.is_empty(),
+        "size": message.source.len(),
+        "downloadUrl": format!("/messages/{}/download", message.id),
+        "createdAt": message.created_at.to_string(),
+        "updatedAt": message.updated_at.to_string(),
+    })
+}
+
+/// The full message served by `/messages/{id}`
+fn message_json(message: &StoredMessage) -> Value {
+    let attachments: Vec<Value> = message
+        .incoming
+        .attachments
+        .iter()
+        .enumerate()
+        .map(|(idx, attachment)| {
+            json!({
+                "id": attachment_id(idx),
+                "filename": attachment.filename,
+                "contentType": attachment.content_type,
+                "disposition": "attachment",
+                "transferEncoding": "base64",
+                "related": false,
+                "size": attachment.data.len(),
+                "downloadUrl": format!("/messages/{}/attachment/{}", message.id, attachment_id(idx)),
+            })
+        })
+        .collect();
+    json!({
+        "@context": "/contexts/Message",
+        "@id": format!("/messages/{}", message.id),
+        "@type": "Message",
+        "id": message.id,
+        "accountId": format!("/accounts/{}", message.account_id),
+        "msgid": format!("<{}@{}>", message.id, DEFAULT_DOMAIN),
+        "from": { "address": message.incoming.from, "name": message.incoming.from_name },
+        "to": [{ "address": message.to, "name": "" }],
+        "cc": [],
+        "bcc": [],
+        "subject": message.incoming.subject,
+        "seen": message.seen,
+        "flagged": false,
+        "isDeleted": false,
+        "verifications": [],
+        "retention": true,
+        "retentionDate": message.created_at.to_string(),
+        "text": message.incoming.text,
+        "html": message.incoming.html,
+        "hasAttachments": !attachments.is_empty(),
+        "attachments": attachments,
+        "size": message.source.len(),
+        "downloadUrl": format!("/messages/{}/download", message.id),
+        "createdAt": message.created_at.to_string(),
+        "updatedAt": message.updated_at.to_string(),
+    })
+}
+
+fn source_json(message: &StoredMessage) -> Value {
+    json!({
+        "@context": "/contexts/Source",
+        "@id": format!("/sources/{}", message.id),
+        "@type": "Source",
+        "id": message.id,
+        "downloadUrl": format!("/messages/{}/download", message.id),
+        "data": message.source,
+    })
+}
+
+fn collection_json(members: Vec<Value>, path: &str, context: &str, page: usize) -> Value {
+    let total = members.len();
+    let last = total.div_ceil(PAGE_SIZE).max(1);
+    let page = page.max(1);
+    let link = |page: usize| format!("{}?page={}", path, page);
+    let mut view = json!({
+        "@id": link(page),
+        "@type": "hydra:PartialCollectionView",
+        "hydra:first": link(1),
+        "hydra:last": link(last),
+    });
+    if page < last {
+        view["hydra:next"] = json!(link(page + 1));
+    }
+    if page > 1 {
+        view["hydra:previous"] = json!(link(page - 1));
+    }
+    json!({
+        "@context": context,
+        "@id": path,
+        "@type": "hydra:Collection",
+        "hydra:member": members.into_iter().skip((page - 1) * PAGE_SIZE).take(PAGE_SIZE).collect::<Vec<_>>(),
+        "hydra:totalItems": total,
+        "hydra:view": view,
+    })
+}
+
+fn attachment_id(idx: usize) -> String {
+    format!("ATTACH{:06}", idx + 1)
+}
+
+/// A reply to a mock request
+struct Reply {
+    status: u16,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn json(status: u16, body: &Value) -> Reply {
+        Reply {
+            status,
+            content_type: "application/ld+json; charset=utf-8".to_string(),
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn empty() -> Reply {
+        Reply {
+            status: 204,
+            content_type: "text/plain".to_string(),
+            body: Vec::new(),
+        }
+    }
+
+    fn raw(content_type: &str, body: Vec<u8>) -> Reply {
+        Reply {
+            status: 200,
+            content_type: content_type.to_string(),
+            body,
+        }
+    }
+
+    fn unauthorized(message: &str) -> Reply {
+        Reply::json(401, &json!({ "code": 401, "message": message }))
+    }
+
+    fn error(status: u16, description: &str) -> Reply {
+        Reply::json(status, &json!({
+            "@context": "/contexts/Error",
+            "@type": "hydra:Error",
+            "hydra:title": "An error occurred",
+            "hydra:description": description,
+        }))
+    }
+
+    fn not_found() -> Reply {
+        Reply::error(404, "Not Found")
+    }
+
+    fn violation(property_path: &str, message: &str) -> Reply {
+        Reply::json(422, &json!({
+            "@context": "/contexts/ConstraintViolationList",
+            "@type": "ConstraintViolationList",
+            "hydra:title": "An error occurred",
+            "hydra:description": format!("{}: {}", property_path, message),
+            "violations": [{ "propertyPath": property_path, "message": message }],
+        }))
+    }
+}
+
+fn handle(state: &Mutex<State>, mut request: Request) {
+    let method = request.method().as_str().to_uppercase();
+    let url = request.url().to_string();
+    let token = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "))
+        .map(str::to_string);
+    let mut body = String::new();
+    if let Err(err) = request.as_reader().read_to_string(&mut body) {
+        log::warn!("Mock server failed to read request body: {}", err);
+    }
+
+    log::trace!("Mock server handling {} {}", method, url);
+    let reply = route(&mut lock(state), &method, &url, token.as_deref(), &body);
+
+    let response = Response::from_data(reply.body)
+        .with_status_code(reply.status)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], reply.content_type.as_bytes()).expect("valid content type header"));
+    if let Err(err) = request.respond(response) {
+        log::warn!("Mock server failed to respond: {}", err);
+    }
+}
+
+fn route(state: &mut State, method: &str, url: &str, token: Option<&str>, body: &str) -> Reply {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let page = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("page="))
+        .and_then(|page| page.parse().ok())
+        .unwrap_or(1);
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (method, segments.as_slice()) {
+        ("GET", ["domains"]) => {
+            let domains = state.domains.iter().map(domain_json).collect();
+            Reply::json(200, &collection_json(domains, "/domains", "/contexts/Domain", page))
+        }
+        ("GET", ["domains", id]) => match state.domains.iter().find(|domain| domain.id == *id) {
+            Some(domain) => Reply::json(200, &domain_json(domain)),
+            None => Reply::not_found(),
+        },
+        ("POST", ["accounts"]) => create_account(state, body),
+        ("POST", ["token"]) => login(state, body),
+        (_, _) => {
+            let account_id = match authenticate(state, token) {
+                Ok(account_id) => account_id,
+                Err(reply) => return reply,
+            };
+            authenticated(state, method, segments.as_slice(), page, &account_id, body)
+        }
+    }
+}
+
+fn authenticate(state: &State, token: Option<&str>) -> Result<String, Reply> {
+    let token = token.ok_or_else(|| Reply::unauthorized("JWT Token not found"))?;
+    match state.tokens.get(token) {
+        Some((_, exp)) if *exp <= now() => Err(Reply::unauthorized("Expired JWT Token")),
+        Some((account_id, _)) => Ok(account_id.clone()),
+        None => Err(Reply::unauthorized("Invalid JWT Token")),
+    }
+}
+
+fn authenticated(state: &mut State, method: &str, segments: &[&str], page: usize, account_id: &str, body: &str) -> Reply {
+    let account = match state.accounts.iter().find(|account| account.id == account_id) {
+        Some(account) => account_json(account),
+        None => return Reply::unauthorized("Invalid JWT Token"),
+    };
+
+    match (method, segments) {
+        ("GET", ["me"]) => Reply::json(200, &account),
+        ("GET", ["accounts", id]) if *id == account_id => Reply::json(200, &account),
+        ("DELETE", ["accounts", id]) if *id == account_id => {
+            state.accounts.retain(|account| account.id != account_id);
+            state.messages.retain(|message| message.account_id != account_id);
+            state.tokens.retain(|_, (owner, _)| owner != account_id);
+            Reply::empty()
+        }
+        (_, ["accounts", _]) => Reply::error(403, "Access Denied."),
+        ("GET", ["messages"]) => {
+            let messages = state.inbox(account_id).map(message_summary_json).collect();
+            Reply::json(200, &collection_json(messages, "/messages", "/contexts/Message", page))
+        }
+        (_, ["messages", id, ..]) | (_, ["sources", id]) => {
+            let idx = match state.message(account_id, id) {
+                Some(idx) => idx,
+                None => return Reply::not_found(),
+            };
+            message(state, idx, method, segments, body)
+        }
+        _ => Reply::not_found(),
+    }
+}
+
+fn message(state: &mut State, idx: usize, method: &str, segments: &[&str], body: &str) -> Reply {
+    match (method, segments) {
+        ("GET", ["messages", _]) => Reply::json(200, &message_json(&state.messages[idx])),
+        ("PATCH", ["messages", _]) => {
+            let seen = serde_json::from_str::<Value>(body)
+                .ok()
+                .and_then(|patch| patch.get("seen")?.as_bool());
+            let message = &mut state.messages[idx];
+            if let Some(seen) = seen {
+                message.seen = seen;
+            }
+            message.updated_at = Timestamp::now();
+            Reply::json(200, &message_json(message))
+        }
+        ("DELETE", ["messages", _]) => {
+            state.messages.remove(idx);
+            Reply::empty()
+        }
+        ("GET", ["messages", _, "download"]) => Reply::raw("message/rfc822", state.messages[idx].source.clone().into_bytes()),
+        ("GET", ["messages", _, "attachment", attachment]) => {
+            let attachments = &state.messages[idx].incoming.attachments;
+            match (0..attachments.len()).find(|idx| attachment_id(*idx) == *attachment) {
+                Some(pos) => Reply::raw(&attachments[pos].content_type, attachments[pos].data.clone()),
+                None => Reply::not_found(),
+            }
+        }
+        ("GET", ["sources", _]) => Reply::json(200, &source_json(&state.messages[idx])),
+        _ => Reply::not_found(),
+    }
+}
+
+fn credentials(body: &str) -> Option<(String, String)> {
+    let value: Value = serde_json::from_str(body).ok()?;
+    Some((
+        value.get("address")?.as_str()?.to_string(),
+        value.get("password")?.as_str()?.to_string(),
+    ))
+}
+
+fn create_account(state: &mut State, body: &str) -> Reply {
+    let (address, password) = match credentials(body) {
+        Some(credentials) => credentials,
+        None => return Reply::error(400, "address and password are required"),
+    };
+    let domain = match address.rsplit_once('@') {
+        Some((id, domain)) if !id.is_empty() => domain,
+        _ => return Reply::violation("address", "This value is not a valid email address."),
+    };
+    if !state.domains.iter().any(|d| d.is_active && d.domain.eq_ignore_ascii_case(domain)) {
+        return Reply::violation("address", "This value is not valid.");
+    }
+    if state.account_by_address(&address).is_some() {
+        return Reply::violation("address", "This value is already used.");
+    }
+    if password.len() < 6 {
+        return Reply::violation("password", "This value is too short. It should have 6 characters or more.");
+    }
+
+    let account = StoredAccount {
+        id: random_id(),
+        address: address.to_lowercase(),
+        password,
+        created_at: Timestamp::now(),
+    };
+    let json = account_json(&account);
+    state.accounts.push(account);
+    Reply::json(201, &json)
+}
+
+fn login(state: &mut State, body: &str) -> Reply {
+    let account = credentials(body).and_then(|(address, password)| {
+        state
+            .account_by_address(&address)
+            .filter(|account| account.password == password)
+            .map(|account| (account.id.clone(), account.address.clone()))
+    });
+    match account {
+        Some((id, address)) => {
+            let token = state.issue_token(&id, &address);
+            Reply::json(200, &json!({ "token": token, "id": id }))
+        }
+        None => Reply::unauthorized("Invalid credentials."),
+    }
+}
+
+fn rfc822(to: &str, id: &str, incoming: &Incoming, date: Timestamp) -> String {
+    let from = if incoming.from_name.is_empty() {
+        format!("<{}>", incoming.from)
+    } else {
+        format!("{} <{}>", incoming.from_name, incoming.from)
+    };
+    format!(
+        "From: {}\r\nTo: <{}>\r\nSubject: {}\r\nMessage-ID: <{}@{}>\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+        from, to, incoming.subject, id, DEFAULT_DOMAIN, date, incoming.text
+    )
+}
+
+fn random_id() -> String {
+    let mut rng = rand::thread_rng();
+    (0..24).map(|_| format!("{:x}", rng.gen_range(0..16))).collect()
+}
+
+fn now() -> u64 {
+    Timestamp::now().unix_timestamp().max(0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::User;
+
+    #[test]
+    fn test_mock_server() -> Result<(), MailTmError> {
+        let server = MockServer::start()?;
+        let client = server.client()?;
+        let domain = client.domains()?.any_active().expect("mock domain");
+        assert_eq!(domain.domain, DEFAULT_DOMAIN);
+
+        let user = User::new("someone", "hunter22", DEFAULT_DOMAIN);
+        let account = client.create_account(&user)?;
+        assert!(client.create_account(&user).unwrap_err().is_address_taken());
+        assert!(matches!(client.login("someone@mock.tm", "wrong"), Err(MailTmError::InvalidCredentials(_))));
+
+        let user = client.login(&user.address(), "hunter22")?;
+        assert_eq!(client.me(&user)?.id, account.id);
+
+        let sent = server.deliver(&user.address(), Incoming::new("a@example.com", "Hi")
+            .with_text("Hello")
+            .with_attachment("a.txt", "text/plain", b"attached"))?;
+        assert!(matches!(server.deliver("nobody@mock.tm", Incoming::default()), Err(MailTmError::InvalidAddress(_))));
+
+        let messages = client.list_messages(&user, None)?;
+        assert_eq!(messages.total_items, 1);
+        assert_eq!(messages.members[0].id2, sent.id2);
+        assert_eq!(messages.members[0].intro, "Hello");
+        assert!(messages.members[0].has_attachments && messages.members[0].attachments.is_empty());
+        assert_eq!(client.get_message(&user, &sent.id2)?, sent);
+        assert_eq!(server.messages(&user.address()), vec![sent.clone()]);
+
+        assert!(client.mark_seen(&user, &sent.id2, true)?.seen);
+        assert!(!client.mark_seen(&user, &sent.id2, false)?.seen);
+        assert!(client.get_message_source(&user, &sent.id2)?.contains("Subject: Hi"));
+        assert_eq!(client.get_attachment(&user, &sent.attachments[0])?, b"attached");
+
+        client.delete_message(&user, &sent.id2)?;
+        assert!(matches!(client.get_message(&user, &sent.id2), Err(MailTmError::NotFound(_))));
+
+        client.delete_account(&user, account.id.as_deref().unwrap())?;
+        assert!(server.accounts().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_paging() -> Result<(), MailTmError> {
+        let server = MockServer::start()?;
+        for idx in 0..40 {
+            server.add_domain(&format!("{}.example.com", idx), idx % 2 == 0);
+        }
+        let client = server.client()?;
+
+        let first = client.domains()?;
+        assert_eq!(first.total_items, 41);
+        assert_eq!(first.members.len(), PAGE_SIZE);
+        assert_eq!(client.list_domains(2)?.members.len(), 11);
+        assert_eq!(client.iter_domains().count(), 41);
+        assert!(client.find_domain("39.example.com")?.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+//! Client side rate limiting
+//!
+//! The public api allows a handful of requests per second from each address and answers anything
+//! beyond that with a 429. A [`RateLimiter`] set through
+//! [`crate::MailTmClientBuilder::with_rate_limiter`] spaces requests out with a token bucket per
+//! host, delaying them before they are sent instead of letting them fail.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How many requests a host accepts
+///
+/// Allows `per_second` requests a second on average, and bursts of up to `burst` requests after a
+/// quiet spell.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    per_second: f64,
+    burst: u32,
+}
+
+impl RateLimit {
+    /// `per_second` requests a second, in bursts of as many, at most 1 if less than that
+    ///
+    /// # Panics
+    /// If `per_second` is not a positive, finite number.
+    pub fn per_second(per_second: f64) -> RateLimit {
+        assert!(per_second.is_finite() && per_second > 0.0, "rate limit must be positive, got {}", per_second);
+        RateLimit {
+            per_second,
+            burst: (per_second.floor() as u32).max(1),
+        }
+    }
+
+    pub fn with_burst(self, burst: u32) -> RateLimit {
+        RateLimit {
+            burst: burst.max(1),
+            ..self
+        }
+    }
+}
+
+/// The longest a single request is held back, however low the rate
+const MAX_WAIT: Duration = Duration::from_secs(3600);
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Takes a token, going into debt when there are none, and returns how long to wait for it
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated = self.updated.max(now);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::try_from_secs_f64(-self.tokens / limit.per_second)
+                .unwrap_or(MAX_WAIT)
+                .min(MAX_WAIT)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Limits {
+    default: Option<RateLimit>,
+    hosts: HashMap<String, RateLimit>,
+}
+
+/// Token buckets for every host a client talks to
+///
+/// Hosts without a limit of their own use the default limit, or are not limited at all if there is
+/// none. Cloning is cheap and clones share their buckets, as do all clones of a client, so a limiter
+/// can also be handed to several clients that should stay under one limit together.
+///
+/// # Example
+/// ```no_run
+/// use mail_tm_rs::MailTmClient;
+/// use mail_tm_rs::rate_limit::{RateLimit, RateLimiter};
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let limiter = RateLimiter::default()
+///         .with_limit(RateLimit::per_second(8.0))
+///         .with_host_limit("api.mail.gw", RateLimit::per_second(4.0).with_burst(2));
+///     let client = MailTmClient::builder()?.with_rate_limiter(limiter).build()?;
+///     client.domains()?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    limits: Arc<Limits>,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    /// Limits every host that has no limit of its own
+    pub fn with_limit(self, limit: RateLimit) -> RateLimiter {
+        let mut limits = Limits::clone(&self.limits);
+        limits.default = Some(limit);
+        self.with_limits(limits)
+    }
+
+    /// Limits requests to `host`, such as `api.mail.tm`
+    pub fn with_host_limit(self, host: &str, limit: RateLimit) -> RateLimiter {
+        let mut limits = Limits::clone(&self.limits);
+        limits.hosts.insert(host.to_ascii_lowercase(), limit);
+        self.with_limits(limits)
+    }
+
+    /// Changing the limits starts from fresh buckets, no longer shared with earlier clones
+    fn with_limits(self, limits: Limits) -> RateLimiter {
+        RateLimiter {
+            limits: Arc::new(limits),
+            buckets: Arc::default(),
+        }
+    }
+
+    /// The limit applied to `host`, if any
+    pub fn limit(&self, host: &str) -> Option<RateLimit> {
+        self.limits
+            .hosts
+            .get(&host.to_ascii_lowercase())
+            .copied()
+            .or(self.limits.default)
+    }
+
+    /// Reserves a request to `host`, returning how long to wait before sending it
+    pub fn acquire(&self, host: &str) -> Duration {
+        self.acquire_at(host, Instant::now())
+    }
+
+    fn acquire_at(&self, host: &str, now: Instant) -> Duration {
+        let limit = match self.limit(host) {
+            Some(limit) => limit,
+            None => return Duration::ZERO,
+        };
+        let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+        buckets
+            .entry(host.to_ascii_lowercase())
+            .or_insert_with(|| Bucket {
+                tokens: limit.burst as f64,
+                updated: now,
+            })
+            .take(&limit, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::MailTmClient;
+    use crate::error::MailTmError;
+    use crate::transport::MemoryTransport;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::default()
+            .with_limit(RateLimit::per_second(2.0))
+            .with_host_limit("API.mail.gw", RateLimit::per_second(1.0).with_burst(3));
+        let start = Instant::now();
+
+        assert_eq!(limiter.acquire_at("api.mail.tm", start), Duration::ZERO);
+        assert_eq!(limiter.acquire_at("api.mail.tm", start), Duration::ZERO);
+        assert_eq!(limiter.acquire_at("api.mail.tm", start), Duration::from_millis(500));
+        assert_eq!(limiter.acquire_at("api.mail.tm", start), Duration::from_secs(1));
+        assert_eq!(limiter.acquire_at("api.mail.tm", start + Duration::from_secs(3)), Duration::ZERO);
+
+        for _ in 0..3 {
+            assert_eq!(limiter.acquire_at("api.mail.gw", start), Duration::ZERO);
+        }
+        assert_eq!(limiter.acquire_at("api.mail.gw", start), Duration::from_secs(1));
+
+        let clone = limiter.clone();
+        assert_eq!(clone.acquire_at("api.mail.gw", start), Duration::from_secs(2));
+        assert_eq!(RateLimiter::default().acquire_at("api.mail.tm", start), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_tiny_rates() {
+        let start = Instant::now();
+        let limiter = RateLimiter::default().with_limit(RateLimit::per_second(1e-20));
+        assert_eq!(limiter.acquire_at("a", start), Duration::ZERO);
+        assert_eq!(limiter.acquire_at("a", start), MAX_WAIT);
+        assert_eq!(limiter.acquire_at("a", start), MAX_WAIT);
+
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(std::panic::catch_unwind(|| RateLimit::per_second(rate)).is_err(), "{}", rate);
+        }
+    }
+
+    #[test]
+    fn test_client_rate_limit() -> Result<(), MailTmError> {
+        let transport = MemoryTransport::with_handler(|_| crate::http::Response::new(br#"{"hydra:member":[],"hydra:totalItems":0}"#.to_vec()));
+        let client = MailTmClient::builder()?
+            .with_base_url("http://localhost")
+            .with_transport(transport.clone())
+            .with_rate_limit(RateLimit::per_second(20.0).with_burst(1))
+            .build()?;
+
+        let start = Instant::now();
+        let clone = client.clone();
+        for _ in 0..2 {
+            client.list_domains(1)?;
+            clone.list_domains(1)?;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert_eq!(transport.requests().len(), 4);
+        Ok(())
+    }
+}
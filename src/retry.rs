@@ -0,0 +1,268 @@
+//! Retrying rate limited and failed requests
+//!
+//! Mail-TM answers bursts of requests with a 429 and occasionally fails with a 5xx. A
+//! [`RetryPolicy`] set through [`crate::MailTmClientBuilder::with_retry_policy`] retries those
+//! requests with exponential backoff, waiting as long as the `Retry-After` header asks for when the
+//! api sends one. Clients send every request once unless given a policy.
+//!
+//! Only requests that are safe to send twice are retried after a server error or a failed
+//! connection, as the first attempt may have gone through. A `POST /accounts` that timed out may
+//! well have created the account. Rate limited requests were turned away before doing anything and
+//! are always retried.
+
+use std::time::{Duration, SystemTime};
+
+use rand::Rng;
+
+use crate::http::header::RETRY_AFTER;
+use crate::http::{HeaderMap, Method, Request, StatusCode};
+use crate::timestamp;
+
+/// How often and how long to wait before sending a request again
+///
+/// The default makes up to 3 attempts, waiting about half a second before the second and a second
+/// before the third. [`RetryPolicy::none`], what clients use unless told otherwise, turns retries off.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+    respect_retry_after: bool,
+    retry_non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: true,
+            respect_retry_after: true,
+            retry_non_idempotent: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Sends every request exactly once
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::default().with_max_attempts(1)
+    }
+
+    /// Attempts in total, including the first, at least 1
+    pub fn with_max_attempts(self, max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            ..self
+        }
+    }
+
+    /// The wait before the first retry, doubled for every retry after that
+    pub fn with_base_delay(self, base_delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            base_delay,
+            ..self
+        }
+    }
+
+    /// The longest wait between two attempts
+    ///
+    /// A `Retry-After` asking for longer than this ends the retries straight away.
+    pub fn with_max_delay(self, max_delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_delay,
+            ..self
+        }
+    }
+
+    /// Randomises each wait between half and all of the backoff, on by default
+    pub fn with_jitter(self, jitter: bool) -> RetryPolicy {
+        RetryPolicy {
+            jitter,
+            ..self
+        }
+    }
+
+    /// Waits for as long as a `Retry-After` header asks for instead of the backoff, on by default
+    pub fn with_respect_retry_after(self, respect_retry_after: bool) -> RetryPolicy {
+        RetryPolicy {
+            respect_retry_after,
+            ..self
+        }
+    }
+
+    /// Also retries requests such as `POST` after a server error or a failed connection
+    ///
+    /// Off by default, as those may have taken effect already and would be applied twice.
+    pub fn with_retry_non_idempotent(self, retry_non_idempotent: bool) -> RetryPolicy {
+        RetryPolicy {
+            retry_non_idempotent,
+            ..self
+        }
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// The backoff before attempt `attempt + 1`, without jitter
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.base_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    /// How long to wait before sending the request again, `None` if it should not be
+    ///
+    /// `status` is `None` when the request failed without a response. `retry_after` is only read
+    /// once the request is known to be retried.
+    pub(crate) fn next_delay<F: FnOnce() -> Option<Duration>>(&self, method: &Method, attempt: u32, status: Option<StatusCode>, retry_after: F) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let replayable = method.is_idempotent() || self.retry_non_idempotent;
+        let retryable = match status {
+            Some(StatusCode::TOO_MANY_REQUESTS) => true,
+            Some(StatusCode::INTERNAL_SERVER_ERROR)
+            | Some(StatusCode::BAD_GATEWAY)
+            | Some(StatusCode::SERVICE_UNAVAILABLE)
+            | Some(StatusCode::GATEWAY_TIMEOUT) => replayable,
+            Some(_) => false,
+            None => replayable,
+        };
+        if !retryable {
+            return None;
+        }
+        let retry_after = if self.respect_retry_after { retry_after() } else { None };
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after),
+            None => Some(self.jittered(self.backoff(attempt))),
+        }
+    }
+
+    fn jittered(&self, delay: Duration) -> Duration {
+        if !self.jitter || delay.is_zero() {
+            return delay;
+        }
+        let half = delay / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+}
+
+/// The wait a `Retry-After` header asks for, given either in seconds or as an http date
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = timestamp::parse_http_date(value)?;
+    Some(date.system_time().duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// A copy of the request to send, as http requests cannot be cloned
+pub(crate) fn copy_request(request: &Request<String>) -> Request<String> {
+    let mut copy = Request::new(request.body().clone());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.version_mut() = request.version();
+    *copy.headers_mut() = request.headers().clone();
+    copy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::MailTmClient;
+    use crate::error::MailTmError;
+    use crate::transport::MemoryTransport;
+
+    #[test]
+    fn test_next_delay() {
+        let policy = RetryPolicy::default()
+            .with_base_delay(Duration::from_secs(1))
+            .with_max_delay(Duration::from_secs(5))
+            .with_jitter(false);
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff(40), Duration::from_secs(5));
+
+        let too_many = Some(StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(policy.next_delay(&Method::POST, 1, too_many, || None), Some(Duration::from_secs(1)));
+        assert_eq!(policy.next_delay(&Method::GET, 2, too_many, || Some(Duration::from_secs(3))), Some(Duration::from_secs(3)));
+        assert_eq!(policy.next_delay(&Method::GET, 1, too_many, || Some(Duration::from_secs(60))), None);
+        assert_eq!(policy.next_delay(&Method::GET, 3, too_many, || None), None);
+
+        let unavailable = Some(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(policy.next_delay(&Method::DELETE, 1, unavailable, || None), Some(Duration::from_secs(1)));
+        assert_eq!(policy.next_delay(&Method::POST, 1, unavailable, || None), None);
+        assert_eq!(policy.next_delay(&Method::POST, 1, None, || None), None);
+        assert_eq!(policy.next_delay(&Method::GET, 1, Some(StatusCode::NOT_FOUND), || None), None);
+        assert!(policy.clone().with_retry_non_idempotent(true).next_delay(&Method::POST, 1, None, || None).is_some());
+        assert_eq!(RetryPolicy::none().next_delay(&Method::GET, 1, too_many, || None), None);
+        assert_eq!(RetryPolicy::none().next_delay(&Method::GET, 1, too_many, || unreachable!("Retry-After read")), None);
+        assert_eq!(policy.next_delay(&Method::GET, 1, Some(StatusCode::OK), || unreachable!("Retry-After read")), None);
+
+        let jittered = RetryPolicy::default().with_base_delay(Duration::from_secs(2));
+        let delay = jittered.next_delay(&Method::GET, 1, too_many, || None).unwrap();
+        assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, "7".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(7)));
+        headers.insert(RETRY_AFTER, "Sun, 06 Nov 1994 08:49:37 GMT".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+        headers.insert(RETRY_AFTER, "soon".parse().unwrap());
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, "Sun, 06 Nov 99999999999999 08:49:37 GMT".parse().unwrap());
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn test_client_retries() -> Result<(), MailTmError> {
+        let transport = MemoryTransport::new();
+        let client = MailTmClient::builder()?
+            .with_base_url("http://localhost")
+            .with_transport(transport.clone())
+            .with_retry_policy(RetryPolicy::default().with_base_delay(Duration::from_millis(1)))
+            .build()?;
+
+        transport.push_response(429, "");
+        transport.push_response(503, "");
+        transport.push_response(200, r#"{"hydra:member":[],"hydra:totalItems":0}"#);
+        assert_eq!(client.list_domains(1)?.total_items, 0);
+        assert_eq!(transport.requests().len(), 3);
+
+        for _ in 0..3 {
+            transport.push_response(429, r#"{"code":429,"message":"Too many requests"}"#);
+        }
+        let err = client.list_domains(1).unwrap_err();
+        assert!(matches!(err, MailTmError::RateLimitExceeded { attempts: 3, .. }), "{:?}", err);
+        assert_eq!(err.status(), Some(429));
+        assert_eq!(transport.requests().len(), 6);
+
+        // A server error on account creation is not retried, the account may exist already
+        transport.push_response(500, "");
+        let user = crate::user::User::default().with_domain("example.com");
+        assert!(matches!(client.create_account(&user), Err(MailTmError::Server(_))));
+        assert_eq!(transport.requests().len(), 7);
+
+        // Clients built without a policy send every request once
+        let client = MailTmClient::builder()?
+            .with_base_url("http://localhost")
+            .with_transport(transport.clone())
+            .build()?;
+        transport.push_response(429, "");
+        assert!(matches!(client.list_domains(1), Err(MailTmError::RateLimited(_))));
+        assert_eq!(transport.requests().len(), 8);
+        Ok(())
+    }
+}
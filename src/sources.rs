@@ -0,0 +1,83 @@
+use std::io::Write;
+
+#[cfg(feature = "async")]
+use futures_lite::AsyncWrite;
+use serde::{Deserialize, Serialize};
+
+use crate::client::MailTmClient;
+use crate::error::MailTmError;
+use crate::http::{Method, Request};
+
+/// The raw source of a message
+///
+/// data: the full RFC 822 message, headers included
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Source {
+    #[serde(rename = "@context")]
+    pub context: Option<String>,
+    #[serde(rename = "@id")]
+    pub at_id: Option<String>,
+    #[serde(rename = "@type")]
+    pub type_field: Option<String>,
+    pub id: String,
+    pub download_url: String,
+    pub data: String,
+}
+
+fn get_request(client: &MailTmClient, token: &str, id: &str) -> Result<Request<String>, MailTmError> {
+    Ok(client
+        .request(Method::GET, &format!("/sources/{}", id), Some(token))
+        .body(String::new())?)
+}
+
+fn download_request(client: &MailTmClient, token: &str, download_url: &str) -> Result<Request<String>, MailTmError> {
+    Ok(client
+        .request(Method::GET, download_url, Some(token))
+        .header("Accept", "message/rfc822")
+        .body(String::new())?)
+}
+
+pub(crate) fn get(client: &MailTmClient, token: &str, id: &str) -> Result<Source, MailTmError> {
+    log::debug!("Getting source for message with id {}", id);
+    client.call(get_request(client, token, id)?)
+}
+
+pub(crate) fn download<W: Write>(client: &MailTmClient, token: &str, download_url: &str, writer: W) -> Result<u64, MailTmError> {
+    log::debug!("Downloading message source from {}", download_url);
+    client.download(download_request(client, token, download_url)?, writer)
+}
+
+#[cfg(feature = "async")]
+pub(crate) async fn get_async(client: &MailTmClient, token: &str, id: &str) -> Result<Source, MailTmError> {
+    log::debug!("Getting source for message with id {}", id);
+    client.call_async(get_request(client, token, id)?).await
+}
+
+#[cfg(feature = "async")]
+pub(crate) async fn download_async<W: AsyncWrite + Unpin>(client: &MailTmClient, token: &str, download_url: &str, writer: W) -> Result<u64, MailTmError> {
+    log::debug!("Downloading message source from {}", download_url);
+    client.download_async(download_request(client, token, download_url)?, writer).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_deserialize() -> Result<(), serde_json::Error> {
+        let source: Source = serde_json::from_str(r#"{
+            "@context": "/contexts/Source",
+            "@id": "/sources/abc",
+            "@type": "Source",
+            "id": "abc",
+            "downloadUrl": "/messages/abc/download",
+            "data": "Subject: hi\r\n\r\nhello"
+        }"#)?;
+
+        assert_eq!(source.id, "abc");
+        assert_eq!(source.download_url, "/messages/abc/download");
+        assert!(source.data.starts_with("Subject: hi"));
+        Ok(())
+    }
+}
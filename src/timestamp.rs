@@ -0,0 +1,368 @@
+//! Dates as sent by the api
+//!
+//! Every `createdAt`/`updatedAt` is an RFC 3339 string. [`Timestamp`] parses those into an
+//! instant that can be compared, sorted and measured without any extra dependencies. With the
+//! `chrono` or `time` features enabled it converts into the types of those crates as well.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::error::MailTmError;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// A point in time, normalised to UTC
+///
+/// Serialises back to RFC 3339 in the `+00:00` form the api uses. The default is the unix epoch.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    secs: i64,
+    nanos: u32,
+}
+
+impl Timestamp {
+    /// Parses an RFC 3339 date time such as `2021-05-20T08:21:36+00:00`
+    pub fn parse(input: &str) -> Result<Timestamp, MailTmError> {
+        parse_rfc3339(input).ok_or_else(|| MailTmError::InvalidTimestamp(input.to_string()))
+    }
+
+    pub fn now() -> Timestamp {
+        SystemTime::now().into()
+    }
+
+    /// Seconds since the unix epoch
+    pub fn unix_timestamp(&self) -> i64 {
+        self.secs
+    }
+
+    pub fn system_time(&self) -> SystemTime {
+        if self.secs >= 0 {
+            UNIX_EPOCH + Duration::new(self.secs as u64, self.nanos)
+        } else {
+            UNIX_EPOCH - Duration::from_secs(self.secs.unsigned_abs()) + Duration::from_nanos(self.nanos as u64)
+        }
+    }
+
+    /// How long ago this was, zero if it is in the future
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.system_time())
+            .unwrap_or_default()
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => Timestamp {
+                secs: since.as_secs() as i64,
+                nanos: since.subsec_nanos(),
+            },
+            Err(err) => {
+                let before = err.duration();
+                let (secs, nanos) = (-(before.as_secs() as i64), before.subsec_nanos());
+                if nanos == 0 {
+                    Timestamp { secs, nanos }
+                } else {
+                    Timestamp { secs: secs - 1, nanos: 1_000_000_000 - nanos }
+                }
+            }
+        }
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(timestamp: Timestamp) -> Self {
+        timestamp.system_time()
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = MailTmError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Timestamp::parse(s)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = self.secs.div_euclid(SECONDS_PER_DAY);
+        let time = self.secs.rem_euclid(SECONDS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            time / 3600,
+            time % 3600 / 60,
+            time % 60
+        )?;
+        if self.nanos != 0 {
+            let fraction = format!("{:09}", self.nanos);
+            write!(f, ".{}", fraction.trim_end_matches('0'))?;
+        }
+        f.write_str("+00:00")
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TimestampVisitor;
+
+        impl<'de> Visitor<'de> for TimestampVisitor {
+            type Value = Timestamp;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an RFC 3339 date time")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Timestamp, E> {
+                parse_rfc3339(v).ok_or_else(|| E::invalid_value(de::Unexpected::Str(v), &self))
+            }
+        }
+
+        deserializer.deserialize_str(TimestampVisitor)
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl From<Timestamp> for chrono::DateTime<chrono::Utc> {
+    fn from(timestamp: Timestamp) -> Self {
+        chrono::DateTime::from_timestamp(timestamp.secs, timestamp.nanos).unwrap_or_default()
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl<Tz: chrono::TimeZone> From<chrono::DateTime<Tz>> for Timestamp {
+    fn from(time: chrono::DateTime<Tz>) -> Self {
+        Timestamp {
+            secs: time.timestamp(),
+            nanos: time.timestamp_subsec_nanos(),
+        }
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<Timestamp> for time::OffsetDateTime {
+    fn from(timestamp: Timestamp) -> Self {
+        time::OffsetDateTime::from_unix_timestamp(timestamp.secs)
+            .map(|time| time + time::Duration::nanoseconds(timestamp.nanos as i64))
+            .unwrap_or(time::OffsetDateTime::UNIX_EPOCH)
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<time::OffsetDateTime> for Timestamp {
+    fn from(time: time::OffsetDateTime) -> Self {
+        Timestamp {
+            secs: time.unix_timestamp(),
+            nanos: time.nanosecond(),
+        }
+    }
+}
+
+/// `YYYY-MM-DDTHH:MM:SS[.fraction](Z|+HH:MM|-HH:MM)`, the separator may also be a space
+fn parse_rfc3339(input: &str) -> Option<Timestamp> {
+    let bytes = input.as_bytes();
+    if bytes.len() < 20 || bytes[4] != b'-' || bytes[7] != b'-' || bytes[13] != b':' || bytes[16] != b':' {
+        return None;
+    }
+    if !matches!(bytes[10], b'T' | b't' | b' ') {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = input.get(range)?;
+        if digits.bytes().all(|b| b.is_ascii_digit()) {
+            digits.parse().ok()
+        } else {
+            None
+        }
+    };
+
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let mut rest = &input[19..];
+    let mut nanos = 0u32;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if len == 0 {
+            return None;
+        }
+        let digits = &fraction[..len.min(9)];
+        nanos = digits.parse::<u32>().ok()? * 10u32.pow(9 - digits.len() as u32);
+        rest = &fraction[len..];
+    }
+
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let hours: i64 = rest[1..3].parse().ok()?;
+            let minutes: i64 = rest[4..6].parse().ok()?;
+            sign * (hours * 3600 + minutes * 60)
+        }
+        _ => return None,
+    };
+
+    // Leap seconds are folded into the following second
+    let secs = days_from_civil(year, month, day) * SECONDS_PER_DAY + hour * 3600 + minute * 60 + second - offset;
+    Some(Timestamp { secs, nanos })
+}
+
+/// The IMF-fixdate form of an http date, `Sun, 06 Nov 1994 08:49:37 GMT`
+pub(crate) fn parse_http_date(input: &str) -> Option<Timestamp> {
+    let mut parts = input.split_whitespace();
+    let _weekday = parts.next()?.strip_suffix(',')?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    let month = match parts.next()? {
+        "Jan" => 1,
+        "Feb" => 2,
+        "Mar" => 3,
+        "Apr" => 4,
+        "May" => 5,
+        "Jun" => 6,
+        "Jul" => 7,
+        "Aug" => 8,
+        "Sep" => 9,
+        "Oct" => 10,
+        "Nov" => 11,
+        "Dec" => 12,
+        _ => return None,
+    };
+    let year = parts.next().filter(|year| year.len() == 4 && year.bytes().all(|b| b.is_ascii_digit()))?;
+    let year: i64 = year.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|part| part.parse::<i64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if parts.next()? != "GMT" || parts.next().is_some() || time.next().is_some() {
+        return None;
+    }
+    if day < 1 || day > days_in_month(year, month) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let secs = days_from_civil(year, month, day)
+        .checked_mul(SECONDS_PER_DAY)?
+        .checked_add(hour * 3600 + minute * 60 + second)?;
+    Some(Timestamp { secs, nanos: 0 })
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since the unix epoch, see http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<(), MailTmError> {
+        let timestamp = Timestamp::parse("2021-05-20T08:21:36+00:00")?;
+        assert_eq!(timestamp.unix_timestamp(), 1621498896);
+        assert_eq!(timestamp.to_string(), "2021-05-20T08:21:36+00:00");
+
+        let offset = Timestamp::parse("2021-05-20T10:21:36.250+02:00")?;
+        assert_eq!(offset.unix_timestamp(), 1621498896);
+        assert!(offset > timestamp);
+        assert_eq!(offset.to_string(), "2021-05-20T08:21:36.25+00:00");
+
+        assert_eq!(Timestamp::parse("1969-12-31T23:59:59Z")?.unix_timestamp(), -1);
+        assert_eq!(Timestamp::parse("2024-02-29T00:00:00Z")?.to_string(), "2024-02-29T00:00:00+00:00");
+        assert_eq!(Timestamp::from(Timestamp::parse("1969-12-31T23:59:59.5Z")?.system_time()), Timestamp::parse("1969-12-31T23:59:59.5Z")?);
+
+        for invalid in &["", "2021-05-20", "2021-02-29T00:00:00Z", "2021-05-20T08:21:36", "2021-05-20T08:21:36+0000", "2021-05-20T08:21:36.Z"] {
+            assert!(matches!(Timestamp::parse(invalid), Err(MailTmError::InvalidTimestamp(_))), "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").map(|t| t.unix_timestamp()), Some(784111777));
+        assert_eq!(parse_http_date("Thu, 20 May 2021 08:21:36 GMT"), Timestamp::parse("2021-05-20T08:21:36Z").ok());
+        for invalid in &["", "120", "Sun, 06 Nov 1994 08:49:37", "Sun, 31 Nov 1994 08:49:37 GMT", "Sunday, 06-Nov-94 08:49:37 GMT", "Sun, 06 Nov 99999999999999 08:49:37 GMT", "Sun, 06 Nov +994 08:49:37 GMT"] {
+            assert!(parse_http_date(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_serde() -> Result<(), serde_json::Error> {
+        let timestamps: Vec<Timestamp> = serde_json::from_str(r#"["2022-01-01T00:00:00+00:00", "2021-12-31T23:00:00-02:00"]"#)?;
+        assert!(timestamps[0] < timestamps[1]);
+        assert_eq!(serde_json::to_string(&timestamps[1])?, r#""2022-01-01T01:00:00+00:00""#);
+        assert!(serde_json::from_str::<Timestamp>(r#""yesterday""#).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_chrono() -> Result<(), MailTmError> {
+        let timestamp = Timestamp::parse("2021-05-20T08:21:36.5+00:00")?;
+        let chrono: chrono::DateTime<chrono::Utc> = timestamp.into();
+        assert_eq!(chrono.to_rfc3339(), "2021-05-20T08:21:36.500+00:00");
+        assert_eq!(Timestamp::from(chrono), timestamp);
+        Ok(())
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn test_time() -> Result<(), MailTmError> {
+        let timestamp = Timestamp::parse("2021-05-20T08:21:36.5+00:00")?;
+        let time: time::OffsetDateTime = timestamp.into();
+        assert_eq!(time.unix_timestamp(), 1621498896);
+        assert_eq!(time.nanosecond(), 500_000_000);
+        assert_eq!(Timestamp::from(time), timestamp);
+        Ok(())
+    }
+}
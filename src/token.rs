@@ -1,10 +1,13 @@
-use std::io::Read;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use serde::{Deserialize, Serialize};
 
-use crate::http::{Client};
+use crate::client::MailTmClient;
+use crate::error::{ApiError, MailTmError};
+use crate::http::{self, Method, Request, StatusCode};
 use crate::user::User;
-use crate::MAIL_API_URL;
-use anyhow::Error;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -13,47 +16,181 @@ pub struct Token {
     pub id: String,
 }
 
-pub(crate) fn token(user: &User) -> Result<Token, Error> {
-    let client = Client::new()?
-        .build()?;
+impl Token {
+    /// Decodes the claims of the token, see [`Claims::decode`]
+    pub fn claims(&self) -> Result<Claims, MailTmError> {
+        Claims::decode(&self.token)
+    }
 
-    log::debug!("Getting token for user {:?}", user);
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        expiry(&self.token)
+    }
+
+    /// Whether the token has expired, tokens without a readable expiry are treated as expired
+    pub fn is_expired(&self) -> bool {
+        self.expires_at()
+            .map(|expiry| expiry <= SystemTime::now())
+            .unwrap_or(true)
+    }
+}
+
+/// The payload of a Mail-TM JWT
+///
+/// iat: issued at, seconds since the epoch
+/// exp: expiry, seconds since the epoch
+/// username: the email address of the account
+/// id: the account id
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+    pub iat: Option<u64>,
+    pub exp: Option<u64>,
+    pub username: Option<String>,
+    pub id: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+impl Claims {
+    /// Decodes the payload of a JWT
+    ///
+    /// The signature is not verified, this is only meant for inspecting tokens handed out by the api.
+    pub fn decode(jwt: &str) -> Result<Claims, MailTmError> {
+        let invalid = || MailTmError::InvalidToken(jwt.to_string());
+        let payload = jwt.split('.').nth(1).ok_or_else(invalid)?;
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload.trim_end_matches('='))
+            .map_err(|_| invalid())?;
+        serde_json::from_slice(&payload).map_err(|err| MailTmError::decode(err, &String::from_utf8_lossy(&payload)))
+    }
 
+    pub fn issued_at(&self) -> Option<SystemTime> {
+        self.iat.map(|iat| UNIX_EPOCH + Duration::from_secs(iat))
+    }
+
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.exp.map(|exp| UNIX_EPOCH + Duration::from_secs(exp))
+    }
+}
+
+/// Reads the expiry of a JWT, if it can be decoded
+pub(crate) fn expiry(jwt: &str) -> Option<SystemTime> {
+    Claims::decode(jwt).ok()?.expires_at()
+}
+
+fn token_request(client: &MailTmClient, address: &str, password: &str) -> Result<Request<String>, MailTmError> {
     let create_as_string = serde_json::json!({
-        "address": format!("{}@{}", user.id, user.domain).to_lowercase(),
-        "password": user.password
+        "address": address.to_lowercase(),
+        "password": password
     });
 
-    let mut res = client
-        .post(format!("{}/token", MAIL_API_URL.to_owned()).as_str(), create_as_string.to_string())?;
+    Ok(client
+        .request(Method::POST, "/token", None)
+        .body(create_as_string.to_string())?)
+}
+
+fn parse_token(code: StatusCode, body: &str) -> Result<Token, MailTmError> {
+    if code == StatusCode::UNAUTHORIZED {
+        return Err(MailTmError::InvalidCredentials(ApiError::parse(code.as_u16(), body)));
+    }
+    http::check_response_status(&code, body)?;
+
+    serde_json::from_str(body).map_err(|err| MailTmError::decode(err, body))
+}
+
+pub(crate) fn token(client: &MailTmClient, user: &User) -> Result<Token, MailTmError> {
+    log::debug!("Getting token for user {:?}", user);
+    token_for(client, &user.address(), &user.password)
+}
 
-    let body = {
-        let mut buffer = String::new();
-        res.body_mut().read_to_string(&mut buffer)?;
-        buffer
-    };
+pub(crate) fn token_for(client: &MailTmClient, address: &str, password: &str) -> Result<Token, MailTmError> {
+    let (code, body) = client.send(token_request(client, address, password)?)?;
     log::trace!("Retrieved email token: {:?}", body);
 
-    Ok(serde_json::from_str(&body)?)
+    parse_token(code, &body)
+}
+
+pub(crate) fn login(client: &MailTmClient, address: &str, password: &str) -> Result<User, MailTmError> {
+    log::debug!("Logging in as {}", address);
+    let user = User::from_address(address, password)?;
+    let token = token_for(client, address, password)?;
+    Ok(crate::update_token(&user, &token.token))
+}
+
+#[cfg(feature = "async")]
+pub(crate) async fn token_async(client: &MailTmClient, user: &User) -> Result<Token, MailTmError> {
+    log::debug!("Getting token for user {:?}", user);
+    token_for_async(client, &user.address(), &user.password).await
+}
+
+#[cfg(feature = "async")]
+pub(crate) async fn token_for_async(client: &MailTmClient, address: &str, password: &str) -> Result<Token, MailTmError> {
+    let (code, body) = client.send_async(token_request(client, address, password)?).await?;
+    log::trace!("Retrieved email token: {:?}", body);
+
+    parse_token(code, &body)
+}
+
+#[cfg(feature = "async")]
+pub(crate) async fn login_async(client: &MailTmClient, address: &str, password: &str) -> Result<User, MailTmError> {
+    log::debug!("Logging in as {}", address);
+    let user = User::from_address(address, password)?;
+    let token = token_for_async(client, address, password).await?;
+    Ok(crate::update_token(&user, &token.token))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock::MockServer;
     use crate::accounts;
 
-    fn test_token() -> Result<(), Error> {
+    #[test]
+    fn test_token() -> Result<(), MailTmError> {
         pretty_env_logger::try_init().ok();
-        let user = User::default().with_domain(&crate::domains::domains()?.any().domain);
+        let server = MockServer::start()?;
+        let client = server.client()?;
+        let user = User::default().with_domain(&crate::domains::domains(&client)?.any_active().expect("no active domains").domain);
 
-        let create = accounts::create(&user).unwrap();
+        let create = accounts::create(&client, &user).unwrap();
 
-        let token = token(&user).unwrap();
+        let token = token(&client, &user).unwrap();
 
-        assert_eq!(token.token.is_empty(), false);
+        assert!(!token.token.is_empty());
 
-        accounts::delete(&token.token, &create.id.unwrap()).unwrap();
+        accounts::delete(&client, &token.token, &create.id.unwrap()).unwrap();
 
         Ok(())
     }
+
+    #[test]
+    fn test_parse_token() {
+        let err = parse_token(StatusCode::UNAUTHORIZED, r#"{"code":401,"message":"Invalid credentials."}"#).unwrap_err();
+        assert!(matches!(err, MailTmError::InvalidCredentials(ref api) if api.message() == "Invalid credentials."));
+
+        let err = parse_token(StatusCode::TOO_MANY_REQUESTS, "").unwrap_err();
+        assert!(matches!(err, MailTmError::RateLimited(_)));
+
+        let token = parse_token(StatusCode::OK, r#"{"token":"abc","id":"123"}"#).unwrap();
+        assert_eq!(token.token, "abc");
+    }
+
+    #[test]
+    fn test_claims() {
+        let payload = URL_SAFE_NO_PAD.encode(r#"{"iat":1700000000,"roles":["ROLE_USER"],"username":"someone@example.com","id":"abc","exp":1700000600}"#);
+        let token = Token {
+            token: format!("eyJ0eXAiOiJKV1QiLCJhbGciOiJSUzI1NiJ9.{}.signature", payload),
+            id: "abc".to_string(),
+        };
+
+        let claims = token.claims().unwrap();
+        assert_eq!(claims.username.as_deref(), Some("someone@example.com"));
+        assert_eq!(claims.id.as_deref(), Some("abc"));
+        assert_eq!(claims.roles, vec!["ROLE_USER"]);
+        assert_eq!(claims.issued_at(), Some(UNIX_EPOCH + Duration::from_secs(1700000000)));
+        assert_eq!(token.expires_at(), Some(UNIX_EPOCH + Duration::from_secs(1700000600)));
+        assert!(token.is_expired());
+
+        assert!(matches!(Claims::decode("garbage"), Err(MailTmError::InvalidToken(_))));
+        assert!(Token::default().is_expired());
+    }
 }
@@ -0,0 +1,345 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::error::MailTmError;
+use crate::http::{Request, Response};
+use crate::transport::{Body, Transport};
+
+/// Recorded traffic, stored as json
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+/// One request and the response the server gave it
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Interaction {
+    pub request: RecordedExchange,
+    pub response: RecordedResponse,
+}
+
+/// The parts of a request used to match it on replay
+///
+/// uri: the path and query, without the host, so a cassette can be replayed against any base url
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedExchange {
+    pub method: String,
+    pub uri: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub body: String,
+}
+
+/// body: the response body, base64 encoded when `base64` is set as it was not valid utf-8
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub base64: bool,
+}
+
+impl Cassette {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Cassette, MailTmError> {
+        let json = fs::read_to_string(path)?;
+        serde_json::from_str(&json).map_err(|err| MailTmError::decode(err, &json))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), MailTmError> {
+        let json = serde_json::to_string_pretty(self).map_err(|err| MailTmError::decode(err, ""))?;
+        Ok(fs::write(path, json)?)
+    }
+}
+
+impl RecordedExchange {
+    fn from_request(request: &Request<String>) -> RecordedExchange {
+        RecordedExchange {
+            method: request.method().to_string(),
+            uri: request
+                .uri()
+                .path_and_query()
+                .map(|path| path.to_string())
+                .unwrap_or_else(|| request.uri().to_string()),
+            body: request.body().clone(),
+        }
+    }
+
+    /// Same method and uri, and bodies that are equal, as json when both parse
+    fn matches(&self, other: &RecordedExchange) -> bool {
+        if self.method != other.method || self.uri != other.uri {
+            return false;
+        }
+        match (
+            serde_json::from_str::<serde_json::Value>(&self.body),
+            serde_json::from_str::<serde_json::Value>(&other.body),
+        ) {
+            (Ok(ours), Ok(theirs)) => ours == theirs,
+            _ => self.body == other.body,
+        }
+    }
+}
+
+impl RecordedResponse {
+    fn from_response(response: &Response<Vec<u8>>) -> RecordedResponse {
+        let headers = response
+            .headers()
+            .keys()
+            .map(|name| {
+                let values: Vec<_> = response
+                    .headers()
+                    .get_all(name)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .collect();
+                (name.to_string(), values.join(", "))
+            })
+            .collect();
+        let (body, base64) = match String::from_utf8(response.body().clone()) {
+            Ok(body) => (body, false),
+            Err(err) => (STANDARD.encode(err.as_bytes()), true),
+        };
+        RecordedResponse {
+            status: response.status().as_u16(),
+            headers,
+            body,
+            base64,
+        }
+    }
+
+    fn to_response(&self) -> Result<Response<Vec<u8>>, MailTmError> {
+        let body = if self.base64 {
+            STANDARD
+                .decode(&self.body)
+                .map_err(|err| MailTmError::Transport(Box::new(err)))?
+        } else {
+            self.body.clone().into_bytes()
+        };
+        let mut builder = Response::builder().status(self.status);
+        for (name, value) in &self.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        Ok(builder.body(body)?)
+    }
+}
+
+enum Mode {
+    Record {
+        inner: Arc<dyn Transport>,
+        path: Option<PathBuf>,
+    },
+    Replay {
+        used: Vec<bool>,
+    },
+}
+
+struct Inner {
+    mode: Mode,
+    cassette: Cassette,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Mode::Record { path: Some(path), .. } = &self.mode {
+            if let Err(err) = self.cassette.save(path) {
+                log::warn!("Failed to save cassette to {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+/// Records real traffic into a [`Cassette`] and serves it back later
+///
+/// When recording, requests are forwarded to another transport and every exchange is kept, to
+/// be written to the cassette file once the last clone of the transport is dropped, or earlier
+/// with [`CassetteTransport::save`].
+///
+/// When replaying, each request is answered with the first unused interaction recorded for the
+/// same method, path, query and body, so repeated requests play back in the order they were
+/// recorded. A request with no match fails with [`MailTmError::Transport`].
+///
+/// Cassettes hold whatever was sent, including account passwords and tokens, except for the
+/// `Authorization` header which is never recorded. Replayed tokens keep their original expiry, so
+/// build replaying clients with [`crate::MailTmClientBuilder::with_token_refresh`] turned off.
+///
+/// # Example
+/// ```no_run
+/// use mail_tm_rs::MailTmClient;
+/// use mail_tm_rs::transport::{default_transport, CassetteTransport};
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     // Once, against the real api
+///     let recorder = CassetteTransport::record("tests/cassettes/domains.json", default_transport()?);
+///     let client = MailTmClient::builder()?.with_transport(recorder).build()?;
+///     client.domains()?;
+///     drop(client);
+///
+///     // In tests, without network access
+///     let replay = CassetteTransport::replay("tests/cassettes/domains.json")?;
+///     let client = MailTmClient::builder()?.with_transport(replay).with_token_refresh(false).build()?;
+///     client.domains()?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct CassetteTransport {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl CassetteTransport {
+    /// Forwards requests to `transport`, recording them into the cassette at `path`
+    pub fn record<P: AsRef<Path>, T: Transport + 'static>(path: P, transport: T) -> CassetteTransport {
+        CassetteTransport::recording(Some(path.as_ref().to_path_buf()), transport)
+    }
+
+    /// Records into memory only, see [`CassetteTransport::cassette`]
+    pub fn record_in_memory<T: Transport + 'static>(transport: T) -> CassetteTransport {
+        CassetteTransport::recording(None, transport)
+    }
+
+    fn recording<T: Transport + 'static>(path: Option<PathBuf>, transport: T) -> CassetteTransport {
+        CassetteTransport::new(
+            Mode::Record {
+                inner: Arc::new(transport),
+                path,
+            },
+            Cassette::default(),
+        )
+    }
+
+    /// Serves the cassette at `path`
+    pub fn replay<P: AsRef<Path>>(path: P) -> Result<CassetteTransport, MailTmError> {
+        Ok(CassetteTransport::from_cassette(Cassette::load(path)?))
+    }
+
+    pub fn from_cassette(cassette: Cassette) -> CassetteTransport {
+        let used = vec![false; cassette.interactions.len()];
+        CassetteTransport::new(Mode::Replay { used }, cassette)
+    }
+
+    fn new(mode: Mode, cassette: Cassette) -> CassetteTransport {
+        CassetteTransport {
+            inner: Arc::new(Mutex::new(Inner { mode, cassette })),
+        }
+    }
+
+    /// Everything recorded, or being replayed, so far
+    pub fn cassette(&self) -> Cassette {
+        self.inner().cassette.clone()
+    }
+
+    /// Writes the recording to its file now rather than when the transport is dropped
+    pub fn save(&self) -> Result<(), MailTmError> {
+        let inner = self.inner();
+        match &inner.mode {
+            Mode::Record { path: Some(path), .. } => inner.cassette.save(path),
+            _ => Ok(()),
+        }
+    }
+
+    fn inner(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Transport for CassetteTransport {
+    fn open(&self, request: Request<String>) -> Result<Response<Body>, MailTmError> {
+        self.send(request)
+            .map(|response| response.map(|body| Box::new(Cursor::new(body)) as Body))
+    }
+
+    fn send(&self, request: Request<String>) -> Result<Response<Vec<u8>>, MailTmError> {
+        let exchange = RecordedExchange::from_request(&request);
+
+        let recorder = match &self.inner().mode {
+            Mode::Record { inner, .. } => Some(inner.clone()),
+            Mode::Replay { .. } => None,
+        };
+        if let Some(transport) = recorder {
+            // The lock is not held while the request is in flight
+            let response = transport.send(request)?;
+            log::trace!("Recording {} {}", exchange.method, exchange.uri);
+            self.inner().cassette.interactions.push(Interaction {
+                request: exchange,
+                response: RecordedResponse::from_response(&response),
+            });
+            return Ok(response);
+        }
+
+        let mut inner = self.inner();
+        let Inner { mode, cassette } = &mut *inner;
+        let used = match mode {
+            Mode::Replay { used } => used,
+            Mode::Record { .. } => unreachable!("recording handled above"),
+        };
+        let idx = cassette
+            .interactions
+            .iter()
+            .enumerate()
+            .position(|(idx, interaction)| !used[idx] && interaction.request.matches(&exchange))
+            .ok_or_else(|| MailTmError::Transport(format!("no recorded interaction for {} {}", exchange.method, exchange.uri).into()))?;
+        used[idx] = true;
+        log::trace!("Replaying {} {}", exchange.method, exchange.uri);
+        cassette.interactions[idx].response.to_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::MailTmClient;
+    use crate::mock::{Incoming, MockServer};
+    use crate::transport::default_transport;
+    use crate::user::User;
+
+    #[test]
+    fn test_record_and_replay() -> Result<(), MailTmError> {
+        let path = std::env::temp_dir().join(format!("mail-tm-rs-cassette-{}.json", User::default().address()));
+        let server = MockServer::start()?;
+
+        let recorder = CassetteTransport::record(&path, default_transport()?);
+        let client = MailTmClient::builder()?
+            .with_base_url(server.url())
+            .with_transport(recorder.clone())
+            .build()?;
+        let created = User::default().with_domain("mock.tm");
+        client.create_account(&created)?;
+        let user = client.login(&created.address(), &created.password)?;
+        let empty = client.list_messages(&user, None)?;
+        server.deliver(&user.address(), Incoming::new("a@example.com", "Recorded").with_attachment("a.bin", "application/octet-stream", &[0xff, 0x00]))?;
+        let full = client.list_messages(&user, None)?;
+        let message = client.get_message(&user, &full.members[0].id2)?;
+        let attachment = client.get_attachment(&user, &message.attachments[0])?;
+        drop(client);
+
+        assert_eq!(recorder.cassette().interactions.len(), 6);
+        assert!(recorder.cassette().interactions[5].response.base64);
+        drop(recorder);
+        drop(server);
+
+        let client = MailTmClient::builder()?
+            .with_base_url("http://replay.invalid")
+            .with_transport(CassetteTransport::replay(&path)?)
+            .with_token_refresh(false)
+            .build()?;
+        client.create_account(&created)?;
+        let replayed = client.login(&user.address(), &user.password)?;
+        assert_eq!(replayed.email_token, user.email_token);
+        assert_eq!(client.list_messages(&replayed, None)?, empty);
+        assert_eq!(client.list_messages(&replayed, None)?, full);
+        assert_eq!(client.get_message(&replayed, &message.id2)?, message);
+        assert_eq!(client.get_attachment(&replayed, &message.attachments[0])?, attachment);
+        assert!(matches!(client.list_messages(&replayed, None), Err(MailTmError::Transport(_))));
+        assert!(matches!(client.login(&user.address(), "other"), Err(MailTmError::Transport(_))));
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+use std::io::Read;
+
+use isahc::HttpClient;
+
+use crate::error::MailTmError;
+use crate::http::{Request, Response};
+#[cfg(feature = "async")]
+use crate::transport::{AsyncBody, BoxFuture};
+use crate::transport::{Body, Transport};
+
+/// The default transport, backed by a pooled isahc [`HttpClient`]
+#[derive(Clone)]
+pub struct IsahcTransport {
+    client: HttpClient,
+}
+
+impl IsahcTransport {
+    pub fn new() -> Result<IsahcTransport, MailTmError> {
+        Ok(IsahcTransport::from_client(HttpClient::new()?))
+    }
+
+    /// Uses an already configured client, for instance one with proxies or timeouts set
+    pub fn from_client(client: HttpClient) -> IsahcTransport {
+        IsahcTransport { client }
+    }
+}
+
+impl Transport for IsahcTransport {
+    fn open(&self, request: Request<String>) -> Result<Response<Body>, MailTmError> {
+        Ok(self.client.send(request)?.map(|body| Box::new(body) as Body))
+    }
+
+    fn send(&self, request: Request<String>) -> Result<Response<Vec<u8>>, MailTmError> {
+        let (parts, mut body) = self.client.send(request)?.into_parts();
+        let mut bytes = Vec::new();
+        body.read_to_end(&mut bytes)?;
+        Ok(Response::from_parts(parts, bytes))
+    }
+
+    #[cfg(feature = "async")]
+    fn open_async(&self, request: Request<String>) -> BoxFuture<'_, Result<Response<AsyncBody>, MailTmError>> {
+        Box::pin(async move {
+            Ok(self
+                .client
+                .send_async(request)
+                .await?
+                .map(|body| Box::new(body) as AsyncBody))
+        })
+    }
+
+    #[cfg(feature = "async")]
+    fn send_async(&self, request: Request<String>) -> BoxFuture<'_, Result<Response<Vec<u8>>, MailTmError>> {
+        use futures_lite::AsyncReadExt;
+
+        Box::pin(async move {
+            let (parts, mut body) = self.client.send_async(request).await?.into_parts();
+            let mut bytes = Vec::new();
+            body.read_to_end(&mut bytes).await?;
+            Ok(Response::from_parts(parts, bytes))
+        })
+    }
+}
+
+impl From<isahc::Error> for MailTmError {
+    fn from(err: isahc::Error) -> Self {
+        MailTmError::Transport(Box::new(err))
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::error::MailTmError;
+use crate::http::{HeaderMap, Method, Request, Response, StatusCode};
+use crate::transport::{Body, Transport};
+
+/// A request as it was handed to a [`MemoryTransport`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub uri: String,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+type Handler = Arc<dyn Fn(&RecordedRequest) -> Response<Vec<u8>> + Send + Sync>;
+
+#[derive(Default)]
+struct Inner {
+    requests: Vec<RecordedRequest>,
+    responses: VecDeque<Response<Vec<u8>>>,
+}
+
+/// A transport that never touches the network
+///
+/// Every request is recorded. It is answered with the oldest queued response, then by the handler
+/// if there is one, and with an empty 404 otherwise. Clones share their requests and responses, so
+/// a clone can be kept to inspect what a client sent.
+///
+/// # Example
+/// ```
+/// use mail_tm_rs::MailTmClient;
+/// use mail_tm_rs::transport::MemoryTransport;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let transport = MemoryTransport::new();
+///     transport.push_response(200, r#"{"hydra:member":[],"hydra:totalItems":0}"#);
+///
+///     let client = MailTmClient::builder()?.with_transport(transport.clone()).build()?;
+///     assert_eq!(client.domains()?.total_items, 0);
+///     assert!(transport.last_request().unwrap().uri.ends_with("/domains"));
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    inner: Arc<Mutex<Inner>>,
+    handler: Option<Handler>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        MemoryTransport::default()
+    }
+
+    /// Answers every request that has no queued response with `handler`
+    pub fn with_handler<F>(handler: F) -> MemoryTransport
+        where F: Fn(&RecordedRequest) -> Response<Vec<u8>> + Send + Sync + 'static {
+        MemoryTransport {
+            handler: Some(Arc::new(handler)),
+            ..MemoryTransport::default()
+        }
+    }
+
+    /// Queues a response for the next request
+    pub fn push_response(&self, status: u16, body: &str) {
+        let mut response = Response::new(body.as_bytes().to_vec());
+        *response.status_mut() = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        self.inner().responses.push_back(response);
+    }
+
+    /// Every request sent so far, oldest first
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.inner().requests.clone()
+    }
+
+    pub fn last_request(&self) -> Option<RecordedRequest> {
+        self.inner().requests.last().cloned()
+    }
+
+    fn inner(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn open(&self, request: Request<String>) -> Result<Response<Body>, MailTmError> {
+        self.send(request)
+            .map(|response| response.map(|body| Box::new(Cursor::new(body)) as Body))
+    }
+
+    fn send(&self, request: Request<String>) -> Result<Response<Vec<u8>>, MailTmError> {
+        let (parts, body) = request.into_parts();
+        let recorded = RecordedRequest {
+            method: parts.method,
+            uri: parts.uri.to_string(),
+            headers: parts.headers,
+            body,
+        };
+        log::trace!("Memory transport answering {} {}", recorded.method, recorded.uri);
+
+        let queued = {
+            let mut inner = self.inner();
+            inner.requests.push(recorded.clone());
+            inner.responses.pop_front()
+        };
+        Ok(queued
+            .or_else(|| self.handler.as_ref().map(|handler| handler(&recorded)))
+            .unwrap_or_else(|| {
+                let mut response = Response::new(Vec::new());
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                response
+            }))
+    }
+}
@@ -0,0 +1,123 @@
+//! Pluggable http backends
+//!
+//! Every request the crate makes goes through a [`Transport`]. isahc is used by default, the
+//! `ureq` and `reqwest` features add backends built on those crates instead. Any other
+//! implementation can be handed to [`crate::MailTmClientBuilder::with_transport`], such as a
+//! [`MemoryTransport`] to inspect the requests the crate builds without a server.
+//!
+//! When several backend features are enabled, isahc is preferred over ureq, then reqwest.
+
+use std::io::Read;
+use std::sync::Arc;
+
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+
+#[cfg(feature = "async")]
+use futures_lite::io::{AssertAsync, AsyncRead};
+
+use crate::error::MailTmError;
+use crate::http::{Request, Response};
+
+mod cassette;
+#[cfg(feature = "isahc")]
+mod isahc_backend;
+mod memory;
+#[cfg(feature = "reqwest")]
+mod reqwest_backend;
+#[cfg(feature = "ureq")]
+mod ureq_backend;
+
+pub use cassette::{Cassette, CassetteTransport, Interaction, RecordedExchange, RecordedResponse};
+#[cfg(feature = "isahc")]
+pub use isahc_backend::IsahcTransport;
+pub use memory::{MemoryTransport, RecordedRequest};
+#[cfg(feature = "reqwest")]
+pub use reqwest_backend::ReqwestTransport;
+#[cfg(feature = "ureq")]
+pub use ureq_backend::UreqTransport;
+
+/// A response body that has not been read yet
+pub type Body = Box<dyn Read + Send>;
+
+#[cfg(feature = "async")]
+pub type AsyncBody = Box<dyn AsyncRead + Send + Unpin>;
+
+#[cfg(feature = "async")]
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Sends http requests on behalf of a [`crate::MailTmClient`]
+///
+/// Requests arrive with the client's default headers already applied. Responses must be handed
+/// back whatever their status, the client takes care of turning failures into errors.
+pub trait Transport: Send + Sync {
+    /// Sends a request, handing back the response with its body unread
+    fn open(&self, request: Request<String>) -> Result<Response<Body>, MailTmError>;
+
+    /// Sends a request and reads the whole body
+    fn send(&self, request: Request<String>) -> Result<Response<Vec<u8>>, MailTmError> {
+        let (parts, mut body) = self.open(request)?.into_parts();
+        let mut bytes = Vec::new();
+        body.read_to_end(&mut bytes)?;
+        Ok(Response::from_parts(parts, bytes))
+    }
+
+    /// Async version of [`Transport::open`]
+    ///
+    /// Unless overridden this runs the blocking call on the current task.
+    #[cfg(feature = "async")]
+    fn open_async(&self, request: Request<String>) -> BoxFuture<'_, Result<Response<AsyncBody>, MailTmError>> {
+        Box::pin(async move {
+            Ok(self
+                .open(request)?
+                .map(|body| Box::new(AssertAsync::new(body)) as AsyncBody))
+        })
+    }
+
+    /// Async version of [`Transport::send`]
+    ///
+    /// Unless overridden this runs the blocking call on the current task.
+    #[cfg(feature = "async")]
+    fn send_async(&self, request: Request<String>) -> BoxFuture<'_, Result<Response<Vec<u8>>, MailTmError>> {
+        Box::pin(async move { self.send(request) })
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    fn open(&self, request: Request<String>) -> Result<Response<Body>, MailTmError> {
+        (**self).open(request)
+    }
+
+    fn send(&self, request: Request<String>) -> Result<Response<Vec<u8>>, MailTmError> {
+        (**self).send(request)
+    }
+
+    #[cfg(feature = "async")]
+    fn open_async(&self, request: Request<String>) -> BoxFuture<'_, Result<Response<AsyncBody>, MailTmError>> {
+        (**self).open_async(request)
+    }
+
+    #[cfg(feature = "async")]
+    fn send_async(&self, request: Request<String>) -> BoxFuture<'_, Result<Response<Vec<u8>>, MailTmError>> {
+        (**self).send_async(request)
+    }
+}
+
+/// The transport of the preferred backend among the enabled features
+pub fn default_transport() -> Result<Arc<dyn Transport>, MailTmError> {
+    #[cfg(feature = "isahc")]
+    return Ok(Arc::new(IsahcTransport::new()?));
+
+    #[cfg(all(feature = "ureq", not(feature = "isahc")))]
+    return Ok(Arc::new(UreqTransport::new()));
+
+    #[cfg(all(feature = "reqwest", not(any(feature = "isahc", feature = "ureq"))))]
+    return Ok(Arc::new(ReqwestTransport::new()?));
+
+    #[cfg(not(any(feature = "isahc", feature = "ureq", feature = "reqwest")))]
+    Err(MailTmError::Transport(
+        "no http backend enabled, enable the isahc, ureq or reqwest feature or provide a transport".into(),
+    ))
+}
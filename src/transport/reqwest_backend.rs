@@ -0,0 +1,45 @@
+use crate::error::MailTmError;
+use crate::http::{Request, Response};
+use crate::transport::{Body, Transport};
+
+/// A transport backed by a blocking [`reqwest::blocking::Client`]
+///
+/// Like any blocking reqwest client it must not be used from within a tokio runtime.
+#[derive(Clone)]
+pub struct ReqwestTransport {
+    client: reqwest::blocking::Client,
+}
+
+impl ReqwestTransport {
+    pub fn new() -> Result<ReqwestTransport, MailTmError> {
+        Ok(ReqwestTransport::from_client(reqwest::blocking::Client::builder().build()?))
+    }
+
+    pub fn from_client(client: reqwest::blocking::Client) -> ReqwestTransport {
+        ReqwestTransport { client }
+    }
+}
+
+impl Transport for ReqwestTransport {
+    fn open(&self, request: Request<String>) -> Result<Response<Body>, MailTmError> {
+        let (parts, body) = request.into_parts();
+        let response = self
+            .client
+            .request(parts.method, parts.uri.to_string())
+            .headers(parts.headers)
+            .body(body)
+            .send()?;
+
+        let mut builder = Response::builder().status(response.status());
+        if let Some(headers) = builder.headers_mut() {
+            *headers = response.headers().clone();
+        }
+        Ok(builder.body(Box::new(response) as Body)?)
+    }
+}
+
+impl From<reqwest::Error> for MailTmError {
+    fn from(err: reqwest::Error) -> Self {
+        MailTmError::Transport(Box::new(err))
+    }
+}
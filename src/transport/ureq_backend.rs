@@ -0,0 +1,57 @@
+use crate::error::MailTmError;
+use crate::http::{Request, Response};
+use crate::transport::{Body, Transport};
+
+/// A transport backed by a [`ureq::Agent`], a pure rust blocking client
+#[derive(Clone)]
+pub struct UreqTransport {
+    agent: ureq::Agent,
+}
+
+impl UreqTransport {
+    pub fn new() -> UreqTransport {
+        UreqTransport::from_agent(ureq::AgentBuilder::new().build())
+    }
+
+    pub fn from_agent(agent: ureq::Agent) -> UreqTransport {
+        UreqTransport { agent }
+    }
+}
+
+impl Default for UreqTransport {
+    fn default() -> Self {
+        UreqTransport::new()
+    }
+}
+
+impl Transport for UreqTransport {
+    fn open(&self, request: Request<String>) -> Result<Response<Body>, MailTmError> {
+        let (parts, body) = request.into_parts();
+        let mut outgoing = self.agent.request(parts.method.as_str(), &parts.uri.to_string());
+        for (name, value) in &parts.headers {
+            let value = value
+                .to_str()
+                .map_err(|err| MailTmError::Transport(Box::new(err)))?;
+            outgoing = outgoing.set(name.as_str(), value);
+        }
+
+        let result = if body.is_empty() {
+            outgoing.call()
+        } else {
+            outgoing.send_string(&body)
+        };
+        // ureq treats 4xx and 5xx as errors, the client wants to see those responses
+        let response = match result {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(ureq::Error::Transport(err)) => return Err(MailTmError::Transport(Box::new(err))),
+        };
+
+        let mut builder = Response::builder().status(response.status());
+        for name in response.headers_names() {
+            for value in response.all(&name) {
+                builder = builder.header(name.as_str(), value);
+            }
+        }
+        Ok(builder.body(Box::new(response.into_reader()) as Body)?)
+    }
+}
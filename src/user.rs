@@ -2,6 +2,8 @@ use serde::{Deserialize, Serialize};
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 
+use crate::error::MailTmError;
+
 /// A global User
 ///
 /// This user is the secret sauce for all things to do with this API. There will be raw counterparts
@@ -32,6 +34,19 @@ impl User {
         }
     }
 
+    /// Builds a user from a full email address such as `someone@example.com`
+    pub fn from_address(address: &str, password: &str) -> Result<User, MailTmError> {
+        match address.rsplit_once('@') {
+            Some((id, domain)) if !id.is_empty() && !domain.is_empty() => Ok(User::new(id, password, domain)),
+            _ => Err(MailTmError::InvalidAddress(address.to_string())),
+        }
+    }
+
+    /// The full email address of the user
+    pub fn address(&self) -> String {
+        format!("{}@{}", self.id, self.domain)
+    }
+
     pub fn with_domain(self, domain: &str) -> User {
         User {
             domain: domain.to_string(),
@@ -54,3 +69,20 @@ impl Default for User {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_address() {
+        let user = User::from_address("someone@example.com", "hunter2").unwrap();
+        assert_eq!(user.id, "someone");
+        assert_eq!(user.domain, "example.com");
+        assert_eq!(user.password, "hunter2");
+        assert_eq!(user.address(), "someone@example.com");
+
+        assert!(matches!(User::from_address("example.com", ""), Err(MailTmError::InvalidAddress(_))));
+        assert!(matches!(User::from_address("someone@", ""), Err(MailTmError::InvalidAddress(_))));
+    }
+}